@@ -1,6 +1,11 @@
 //! speed benchmark
-use criterion::{criterion_group, criterion_main, Criterion};
-use paymentlib::run_from_csv;
+#![allow(missing_docs)] // criterion_group! generates undocumented items.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use paymentlib::{
+    run_from_csv, run_stand_alone_sharded, EngineConfig, EngineEvent,
+    Transaction,
+};
+use std::num::NonZeroUsize;
 
 macro_rules! test_csv {
     ($fname:expr) => {
@@ -14,5 +19,56 @@ pub fn speed_benchmark(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, speed_benchmark);
+/// Deposits, withdrawals and disputes spread over many clients.
+fn workload(clients: u16, transactions: u32) -> Vec<Transaction> {
+    let amount = "1.5".parse().unwrap();
+    (1..=transactions)
+        .map(|tx| {
+            let client = (tx % u32::from(clients)) as u16;
+            match tx % 4 {
+                3 => Transaction::withdrawal(client, tx, amount),
+                // Disputes the deposit made by the same client.
+                2 if tx > u32::from(clients) => {
+                    Transaction::dispute(client, tx - u32::from(clients))
+                }
+                _ => Transaction::deposit(client, tx, amount),
+            }
+        })
+        .collect()
+}
+
+/// Benchmark how the sharded engine scales with the number of shards.
+pub fn shard_benchmark(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let transactions = workload(1000, 200_000);
+    let mut group = c.benchmark_group("shards");
+    group.sample_size(10);
+    for shards in [1, 2, 4, 8] {
+        let shards = NonZeroUsize::new(shards).unwrap();
+        group.bench_with_input(
+            BenchmarkId::from_parameter(shards),
+            &shards,
+            |b, &shards| {
+                b.iter(|| {
+                    runtime.block_on(async {
+                        let handle = run_stand_alone_sharded(
+                            EngineConfig::default(),
+                            shards,
+                        );
+                        for tx in &transactions {
+                            handle
+                                .send(EngineEvent::Tx(tx.clone()))
+                                .await
+                                .unwrap();
+                        }
+                        handle.shutdown().await.unwrap()
+                    })
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, speed_benchmark, shard_benchmark);
 criterion_main!(benches);
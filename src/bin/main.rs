@@ -1,16 +1,280 @@
 //! Main entrypoint for binary.
-use paymentlib::run_from_csv;
-use std::{fs::exists, panic};
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use paymentlib::{
+    generate, replay, run_from_csv_with, run_from_reader, validate,
+    validate_file, verify_journal, DisputePolicy, EngineConfig, EngineError,
+    FileError, HoldPolicy, InputFormat, ParseMode, RunConfig, SyncPolicy,
+    WalConfig, Workload,
+};
+use std::fs::File;
+use std::io::{stdout, BufWriter, Write};
+use std::num::{NonZeroU16, NonZeroU64, NonZeroUsize};
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::Duration;
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "paymentbin",
+    version,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+/// Processes payment transactions and reports the resulting accounts.
+///
+/// Without a subcommand, the arguments are those of `process`.
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    process: ProcessArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Processes transactions and reports the resulting accounts, the default.
+    Process(ProcessArgs),
+    /// Checks every row of the input without processing anything.
+    Validate(ValidateArgs),
+    /// Rebuilds the accounts from a journal and reports them.
+    Replay(ReplayArgs),
+    /// Reports the accounts of persisted state, without new input.
+    Report(ReportArgs),
+    /// Writes a reproducible synthetic workload of transactions.
+    Generate(GenerateArgs),
+}
+
+#[derive(Args, Debug)]
+struct ProcessArgs {
+    /// Transactions to process, `-` reads them from stdin.
+    #[arg(required = true)]
+    input: Option<String>,
+    /// Csv file to write rejected transactions to.
+    #[arg(long, value_name = "PATH")]
+    rejections: Option<String>,
+    #[command(flatten)]
+    input_format: FormatArg,
+    #[command(flatten)]
+    output: OutputArg,
+    /// Stop at the first malformed row instead of skipping it.
+    #[arg(long)]
+    strict: bool,
+    #[command(flatten)]
+    policies: PolicyArgs,
+    /// Partition the accounts over this many engines.
+    #[arg(long, value_name = "N")]
+    shards: Option<NonZeroUsize>,
+    #[command(flatten)]
+    state: StateArgs,
+    /// Rewrite the snapshot every N transactions.
+    #[arg(long, value_name = "N", requires = "snapshot")]
+    snapshot_every: Option<NonZeroU64>,
+    /// When the write-ahead log is synced to disk: `always`, every N
+    /// transactions or every Mms.
+    #[arg(long, value_name = "POLICY", default_value = "always", value_parser = parse_sync)]
+    wal_sync: SyncPolicy,
+}
+
+#[derive(Args, Debug)]
+struct ValidateArgs {
+    /// Transactions to check, `-` reads them from stdin.
+    input: String,
+    #[command(flatten)]
+    input_format: FormatArg,
+}
+
+#[derive(Args, Debug)]
+struct ReplayArgs {
+    /// Journal to rebuild the accounts from.
+    journal: String,
+    /// Check the hash chain of the journal first.
+    #[arg(long)]
+    verify: bool,
+    #[command(flatten)]
+    output: OutputArg,
+    #[command(flatten)]
+    policies: PolicyArgs,
+}
+
+#[derive(Args, Debug)]
+struct ReportArgs {
+    #[command(flatten)]
+    state: StateArgs,
+    #[command(flatten)]
+    output: OutputArg,
+    #[command(flatten)]
+    policies: PolicyArgs,
+}
+
+#[derive(Args, Debug)]
+struct GenerateArgs {
+    /// Number of clients.
+    #[arg(long, default_value_t = NonZeroU16::MIN.saturating_add(999))]
+    clients: NonZeroU16,
+    /// Number of rows.
+    #[arg(short = 'n', long, default_value_t = 100_000)]
+    transactions: u32,
+    /// Share of rows that dispute a recent deposit.
+    #[arg(long, default_value_t = 0.01, value_parser = parse_rate)]
+    dispute_rate: f64,
+    /// Share of disputes ending in a chargeback.
+    #[arg(long, default_value_t = 0.1, value_parser = parse_rate)]
+    chargeback_rate: f64,
+    /// Share of rows that can not be parsed.
+    #[arg(long, default_value_t = 0.0, value_parser = parse_rate)]
+    malformed_ratio: f64,
+    /// Seed, the same seed gives the same rows.
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    #[command(flatten)]
+    output_format: FormatArg,
+    /// File to write the rows to, instead of stdout.
+    #[arg(short, long, value_name = "PATH")]
+    output: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct FormatArg {
+    /// Format of the transactions.
+    #[arg(short, long, value_enum, default_value_t = Format::Csv)]
+    format: Format,
+}
+
+#[derive(Args, Debug)]
+struct OutputArg {
+    /// Csv file to write the accounts to, instead of stdout.
+    #[arg(short, long, value_name = "PATH")]
+    output: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct PolicyArgs {
+    /// Which transactions may be disputed.
+    #[arg(long, value_enum, default_value_t = Dispute::DepositsOnly)]
+    dispute_policy: Dispute,
+    /// Whether disputes may drive available funds negative.
+    #[arg(long, value_enum, default_value_t = Hold::RequireFunds)]
+    hold_policy: Hold,
+}
+
+#[derive(Args, Debug)]
+struct StateArgs {
+    /// SQLite database to persist accounts and transactions to.
+    #[cfg(feature = "sqlite")]
+    #[arg(long, value_name = "PATH")]
+    db: Option<String>,
+    /// Journal to append every transaction to, and rebuild the accounts from.
+    #[arg(long, value_name = "PATH")]
+    journal: Option<String>,
+    /// Snapshot to start from before replaying the journal, rewritten once
+    /// the input is processed.
+    #[arg(long, value_name = "PATH")]
+    snapshot: Option<String>,
+    /// Directory of the write-ahead log to recover from and log to.
+    #[arg(long, value_name = "DIR")]
+    wal: Option<String>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Format {
+    /// Csv with a `type, client, tx, amount` header.
+    Csv,
+    /// One json object per line.
+    Jsonl,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Dispute {
+    /// Only deposits can be disputed.
+    DepositsOnly,
+    /// Only withdrawals can be disputed, with reverse sign.
+    WithdrawalsReverseSign,
+    /// Deposits and withdrawals can be disputed.
+    Both,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Hold {
+    /// Disputes need available funds.
+    RequireFunds,
+    /// Disputes may drive available funds negative.
+    AllowNegative,
+}
+
+impl From<Format> for InputFormat {
+    fn from(format: Format) -> Self {
+        match format {
+            Format::Csv => InputFormat::Csv,
+            Format::Jsonl => InputFormat::JsonLines,
+        }
+    }
+}
+
+impl From<&PolicyArgs> for EngineConfig {
+    fn from(policies: &PolicyArgs) -> Self {
+        EngineConfig {
+            dispute_policy: match policies.dispute_policy {
+                Dispute::DepositsOnly => DisputePolicy::DepositsOnly,
+                Dispute::WithdrawalsReverseSign => {
+                    DisputePolicy::WithdrawalsReverseSign
+                }
+                Dispute::Both => DisputePolicy::Both,
+            },
+            hold_policy: match policies.hold_policy {
+                Hold::RequireFunds => HoldPolicy::RequireFunds,
+                Hold::AllowNegative => HoldPolicy::AllowNegative,
+            },
+        }
+    }
+}
+
+impl StateArgs {
+    /// Sets the persisted state of `config`, the write-ahead log synced
+    /// with `sync`.
+    fn apply(self, config: &mut RunConfig, sync: SyncPolicy) {
+        #[cfg(feature = "sqlite")]
+        {
+            config.database = self.db;
+        }
+        config.journal = self.journal;
+        config.snapshot = self.snapshot;
+        config.wal = self.wal.map(|dir| WalConfig {
+            sync,
+            ..WalConfig::new(dir)
+        });
+    }
+
+    /// Paths of the persisted state that were given.
+    fn paths(&self) -> impl Iterator<Item = &String> {
+        #[cfg(feature = "sqlite")]
+        let db = self.db.as_ref();
+        #[cfg(not(feature = "sqlite"))]
+        let db = None;
+        [
+            db,
+            self.journal.as_ref(),
+            self.snapshot.as_ref(),
+            self.wal.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+    }
+}
+
 /// Main entrypoint of the binary.
-/// Reads file path as an argument from user, returns output to stdout.
+/// Runs the subcommand given, `process` without one, which processes the
+/// input path, `-` for stdin, and writes the accounts to stdout.
+/// See `paymentbin --help` for every subcommand and option.
 ///
-/// Requires : User provides file path in terms of the first argument to the application.
+/// Requires : User provides the input path, or a subcommand and its arguments.
 /// Does : Application provides validated data to stdout.
 /// Ensures :
-/// + Argument exists on 1st spot, store as path.
-/// + File exists in path.
+/// + Arguments are parsed, invalid ones print usage and exit with 2.
+/// + Input files exist in path, or input is read from stdin.
 /// + Call and read output from library.
-/// + Provides output to stdout.
+/// + Provides output to stdout, or the `--output` file.
+/// + Failures are printed to stderr and mapped to a non-zero exit code, see [`exit_code`].
 ///
 /// # Examples
 ///
@@ -18,24 +282,273 @@ use std::{fs::exists, panic};
 ///
 /// ``` sh
 /// cargo run -- transactions.csv > accounts.csv
+/// cargo run -- --rejections rejected.csv transactions.csv > accounts.csv
+/// cargo run -- process --strict --format jsonl -o accounts.csv transactions.jsonl
+/// zcat transactions.csv.gz | cargo run -- - > accounts.csv
+/// cargo run -- validate transactions.csv
+/// cargo run --features sqlite -- --db payments.db monday.csv > accounts.csv
+/// cargo run -- --journal journal.csv --snapshot state.json --snapshot-every 10000 monday.csv
+/// cargo run -- --wal wal --wal-sync 50ms monday.csv > accounts.csv
+/// cargo run -- replay --verify journal.csv
+/// cargo run -- report --snapshot state.json --journal journal.csv
+/// cargo run -- generate -n 100000000 --dispute-rate 0.02 --seed 7 -o soak.csv
 /// ```
 #[tokio::main]
-async fn main() {
-    match std::env::args().nth(1) {
-        Some(path) => {
-            assert!(
-                exists(&path).expect("File does not exist"),
-                "Assertion failed in main: File {:?} does not exist, please make sure to provide a valid path.",
-                &path.as_str()
-            );
-            run_from_csv(&path).await.expect(
-                "Unable to finish reading tx from csv. [engine failed]",
-            );
-        }
-        None => {
-            panic!(
-                "Please provide path, usage example : cargo run -- transactions.csv > accounts.csv"
-            );
+async fn main() -> ExitCode {
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
+    match execute(Cli::parse()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Unable to process transactions: {e}");
+            ExitCode::from(exit_code(&e))
         }
+    }
+}
+
+/// Runs the command of `cli`, `process` without a subcommand.
+async fn execute(cli: Cli) -> Result<(), EngineError> {
+    match cli.command.unwrap_or(Command::Process(cli.process)) {
+        Command::Process(args) => process(args).await,
+        Command::Validate(args) => check(args),
+        Command::Replay(args) => rebuild(args),
+        Command::Report(args) => report(args).await,
+        Command::Generate(args) => synthesize(args),
+    }
+}
+
+/// Processes the input of `args`.
+async fn process(args: ProcessArgs) -> Result<(), EngineError> {
+    let mut config = RunConfig {
+        format: args.input_format.format.into(),
+        output: args.output.output,
+        rejections: args.rejections,
+        parse_mode: if args.strict {
+            ParseMode::Strict
+        } else {
+            ParseMode::Lenient
+        },
+        engine: (&args.policies).into(),
+        shards: args.shards,
+        snapshot_every: args.snapshot_every,
+        ..Default::default()
     };
+    args.state.apply(&mut config, args.wal_sync);
+    // Required by clap unless a subcommand is given.
+    let path = args.input.unwrap_or_default();
+    if path == "-" {
+        run_from_reader(std::io::stdin(), config).await?;
+    } else {
+        run_from_csv_with(&path, config).await?;
+    }
+    Ok(())
+}
+
+/// Validates the input of `args`, printing every issue as csv to stdout
+/// and a summary to stderr.
+fn check(args: ValidateArgs) -> Result<(), EngineError> {
+    let format = args.input_format.format.into();
+    let validation = if args.input == "-" {
+        validate(std::io::stdin(), format)?
+    } else {
+        validate_file(&args.input, format)?
+    };
+    validation.report(stdout())?;
+    eprintln!("{validation}");
+    if validation.is_valid() {
+        Ok(())
+    } else {
+        Err(EngineError::Invalid(validation.issues.len()))
+    }
+}
+
+/// Rebuilds the accounts from the journal of `args` and reports them.
+fn rebuild(args: ReplayArgs) -> Result<(), EngineError> {
+    let head = if args.verify {
+        let file = File::open(&args.journal).map_err(FileError::Journal)?;
+        let head = verify_journal(file)?;
+        eprintln!("Journal intact, head: {head}");
+        Some(head)
+    } else {
+        None
+    };
+    let engine = replay(&args.journal, (&args.policies).into())?;
+    let stream: Box<dyn Write> = match &args.output.output {
+        Some(path) => Box::new(File::create(path).map_err(FileError::StdOut)?),
+        None => Box::new(stdout()),
+    };
+    engine.report_with_head(stream, head.as_deref())?;
+    Ok(())
+}
+
+/// Reports the accounts of the persisted state of `args`.
+/// Every given path must exist, so nothing is created.
+async fn report(args: ReportArgs) -> Result<(), EngineError> {
+    if args.state.paths().next().is_none() {
+        Cli::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "report needs persisted state, e.g. --journal or --snapshot",
+            )
+            .exit();
+    }
+    if let Some(missing) = args.state.paths().find(|p| !Path::new(p).exists()) {
+        return Err(FileError::NotFound(missing.clone()).into());
+    }
+    let mut config = RunConfig {
+        output: args.output.output,
+        engine: (&args.policies).into(),
+        ..Default::default()
+    };
+    args.state.apply(&mut config, SyncPolicy::Always);
+    run_from_reader(std::io::empty(), config).await?;
+    Ok(())
+}
+
+/// Writes the workload of `args`.
+fn synthesize(args: GenerateArgs) -> Result<(), EngineError> {
+    let workload = Workload {
+        clients: args.clients,
+        transactions: args.transactions,
+        dispute_rate: args.dispute_rate,
+        chargeback_rate: args.chargeback_rate,
+        malformed_ratio: args.malformed_ratio,
+        seed: args.seed,
+        format: args.output_format.format.into(),
+    };
+    let stream: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(File::create(path).map_err(FileError::StdOut)?),
+        None => Box::new(stdout().lock()),
+    };
+    generate(&workload, BufWriter::new(stream))?;
+    Ok(())
+}
+
+/// Parses a rate between 0 and 1.
+fn parse_rate(rate: &str) -> Result<f64, String> {
+    match rate.parse::<f64>() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+        _ => Err("expected a number between 0 and 1".to_string()),
+    }
+}
+
+/// Parses a `--wal-sync` policy: `always`, `<n>` or `<m>ms`.
+fn parse_sync(policy: &str) -> Result<SyncPolicy, String> {
+    if policy == "always" {
+        return Ok(SyncPolicy::Always);
+    }
+    match policy.strip_suffix("ms") {
+        Some(ms) => ms
+            .parse()
+            .ok()
+            .map(Duration::from_millis)
+            .map(SyncPolicy::Interval),
+        None => policy.parse().ok().map(SyncPolicy::Every),
+    }
+    .ok_or_else(|| {
+        "expected `always`, a count or a duration like `50ms`".to_string()
+    })
+}
+
+/// Maps an engine error to the exit code of the binary.
+///
+/// | Code | Error                                        |
+/// |------|----------------------------------------------|
+/// | 2    | Invalid arguments                            |
+/// | 3    | Input file not found                         |
+/// | 4    | Input file extension does not match format   |
+/// | 5    | Malformed row in strict mode, invalid input  |
+/// | 6    | Other input/output errors                    |
+/// | 7    | Engine channels or tasks failed              |
+/// | 8    | The database failed                          |
+/// | 9    | The journal hash chain is broken             |
+/// | 10   | Flushing the output failed                   |
+/// | 11   | The payment engine crashed                   |
+fn exit_code(e: &EngineError) -> u8 {
+    match e {
+        EngineError::File(FileError::BrokenChain { .. }) => 9,
+        EngineError::File(FileError::NotFound(_)) => 3,
+        EngineError::File(FileError::WrongExtension(_)) => 4,
+        EngineError::File(FileError::Flush(_)) => 10,
+        EngineError::ParseRow(_)
+        | EngineError::ParseJson { .. }
+        | EngineError::Invalid(_) => 5,
+        EngineError::File(_) => 6,
+        EngineError::Store(_) => 8,
+        EngineError::EngineCrashed(_) => 11,
+        _ => 7,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{execute, Cli};
+    use clap::Parser;
+    use paymentlib::StateSnapshot;
+    use std::path::PathBuf;
+
+    /// A file in the temp dir, removed when dropped. The library's test
+    /// helpers are not visible to the binary.
+    struct Temp(PathBuf);
+    impl Temp {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("paymentbin-{}-{name}", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            Temp(path)
+        }
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+    impl Drop for Temp {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    /// Runs `paymentbin` with `args`, on the csv `rows`.
+    async fn run(args: &[&str], rows: &str) {
+        let input = Temp::new("input.csv");
+        let rows = format!("type, client, tx, amount\n{rows}");
+        std::fs::write(&input.0, rows).unwrap();
+        let cli = Cli::try_parse_from(
+            ["paymentbin"].iter().chain(args).chain([&input.path()]),
+        );
+        assert!(execute(cli.unwrap()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_keeps_tail() {
+        let (snapshot, output) = (Temp::new("s.json"), Temp::new("out.csv"));
+        let state = ["--snapshot", snapshot.path(), "-o", output.path()];
+        let every = [&state[..], &["--snapshot-every", "2"]].concat();
+        let deposits = "deposit, 1, 1, 1\ndeposit, 1, 2, 1\ndeposit, 1, 3, 1\n";
+        run(&every, deposits).await;
+        let taken = StateSnapshot::read(snapshot.path()).unwrap();
+        assert_eq!(taken.position, 3);
+        assert_eq!(taken.accounts[0].total.to_string(), "3");
+        // Without --snapshot-every the snapshot is still written at the end.
+        run(&state, "deposit, 1, 4, 5\n").await;
+        let report = std::fs::read_to_string(&output.0).unwrap();
+        assert_eq!(
+            report,
+            "client,available,held,total,locked\n1,8,0,8,false\n"
+        );
+        assert_eq!(StateSnapshot::read(snapshot.path()).unwrap().position, 4);
+    }
+
+    #[tokio::test]
+    async fn test_rejections_option() {
+        let (rejections, output) =
+            (Temp::new("rejected.csv"), Temp::new("o.csv"));
+        let args = ["--rejections", rejections.path(), "-o", output.path()];
+        run(&args, "withdrawal, 1, 1, 5.0\n").await;
+        let rejected = std::fs::read_to_string(&rejections.0).unwrap();
+        assert_eq!(
+            rejected,
+            "line,reason,row\n2,insufficient_funds,\"withdrawal, 1, 1, 5.0\"\n"
+        );
+    }
 }
@@ -1,174 +1,543 @@
 //! Payment Engine
-use crate::entities::channel::Rx;
-use crate::entities::EngineEvent;
-use crate::errors::EngineError;
+//!
+//! The transaction state machine, it does not depend on an async runtime,
+//! see the `runner` module for the channel based engine task.
+use crate::entities::account::AccountSnapshot;
+use crate::entities::transaction::{
+    Transaction, TransactionSnapshot, TxOutcome,
+};
+use crate::errors::{AccountError, FileError, StoreError, TransactionError};
 use crate::filehandler::csv_to_stdout;
 use std::io::Write;
 
-use super::entities::{account::Account, transaction::TransactionType};
+use crate::store::{AccountStore, History, TransactionStore};
+
+use super::entities::{
+    account::Account,
+    amount::Amount,
+    policy::{EngineConfig, HoldPolicy},
+    transaction::TransactionType,
+};
 
 #[cfg(test)]
 use itertools::Itertools;
 use std::collections::HashMap;
-struct History {
-    amount: f64,
-    dispute: bool,
-}
-impl History {
-    fn new(_amount: f64, _dispute: bool) -> Self {
-        History {
-            amount: _amount,
-            dispute: _dispute,
-        }
-    }
-}
+use tracing::warn;
 
 type Transactions = HashMap<u32, History>; // tx id & History, no need to store the entire transaction.
-type Accounts = HashMap<u16, Account>; // tx id & History, no need to store the entire transaction.
+type Accounts = HashMap<u16, Account>; // client & Account.
 
-struct Engine {
-    account: Accounts,
-    transaction_history: Transactions,
+/// The payment engine, holds every account and the transaction history.
+/// Transactions are applied one at a time with [`Engine::apply`].
+/// Generic over where accounts and transactions are stored, see
+/// [`AccountStore`] and [`TransactionStore`], in memory by default.
+pub struct Engine<A = Accounts, T = Transactions> {
+    config: EngineConfig,
+    account: A,
+    transaction_history: T,
+}
+
+/// Helper macro for decision logic.
+/// There are 2 transaction categories, [deposit, withdrawal] and [dispute, resolve, chargeback]
+/// Macro created to minimize repetition in code.
+/// Expands to a `Result<(), TransactionError>` expression.
+/// Type 1 inputs
+/// + engine => The engine holding the transaction history
+/// + target => Target account
+/// + client => Client performing the transaction
+/// + tx => Transaction id
+/// + amount => Amount
+/// + kind => The transaction type to store in history.
+/// + method => What implemented method to use.
+///
+/// Type 2 inputs
+/// + engine => The engine holding the transaction history
+/// + target => Target account
+/// + client => Client performing the transaction, must own `tx`
+/// + tx => Transaction id
+/// + typename => Transaction type, drives the state transition, see [`TxState::transition`].
+/// + [deposit_method, withdrawal_method] => What implemented `Account` method to use, depending on the disputed transaction.
+macro_rules! process_transaction {
+    // Transaction type 1 (deposit/withdrawal)
+    (transaction_type_1, $engine:expr, $target:expr, $client:expr, $tx:expr, $amount:expr, $kind:expr, $method:ident) => {{
+        if $engine.transaction_history.contains($tx) {
+            // Tx already exists, do not re add it to history.
+            return Err(TransactionError::DuplicateTx($tx));
+        }
+        let amount = $amount.ok_or(TransactionError::MissingAmount($tx))?;
+        if amount <= Amount::ZERO {
+            return Err(TransactionError::NonPositiveAmount($tx));
+        }
+        $target.$method(&amount)?;
+        $engine
+            .transaction_history
+            .insert($tx, History::new($kind, amount, $client));
+        Ok(())
+    }};
+    // Transaction type 2 (dispute/resolve/chargeback)
+    (transaction_type_2, $engine:expr, $target:expr, $client:expr, $tx:expr, $typename:expr, [$deposit_method:expr, $withdrawal_method:expr]) => {{
+        let mut transaction = $engine
+            .transaction_history
+            .get($tx)
+            .ok_or(TransactionError::UnknownTx($tx))?;
+        if transaction.client != $client {
+            // Only the owner may dispute its own transactions.
+            return Err(TransactionError::ClientMismatch {
+                tx: $tx,
+                owner: transaction.client,
+                client: $client,
+            });
+        }
+        if *$typename == TransactionType::Dispute
+            && !$engine.config.dispute_policy.allows(&transaction.kind)
+        {
+            return Err(TransactionError::NotDisputable($tx));
+        }
+        let next = transaction.state.transition($typename).ok_or(
+            TransactionError::InvalidTransition {
+                tx: $tx,
+                state: transaction.state,
+            },
+        )?;
+        match transaction.kind {
+            TransactionType::Withdrawal => {
+                ($withdrawal_method)($target, &transaction.amount)?
+            }
+            _ => ($deposit_method)($target, &transaction.amount)?,
+        };
+        transaction.state = next;
+        $engine.transaction_history.insert($tx, transaction);
+        Ok(())
+    }};
 }
 
 impl Engine {
-    pub(crate) fn new() -> Self {
+    /// Creates an engine without any accounts, stored in memory.
+    pub fn new(config: EngineConfig) -> Self {
+        Engine::with_stores(config, HashMap::new(), HashMap::new())
+    }
+}
+
+impl<A: AccountStore, T: TransactionStore> Engine<A, T> {
+    /// Creates an engine on top of the given stores, they may already
+    /// contain accounts and transactions.
+    pub fn with_stores(
+        config: EngineConfig,
+        accounts: A,
+        transactions: T,
+    ) -> Self {
         Engine {
-            account: HashMap::new(),
-            transaction_history: HashMap::new(),
+            config,
+            account: accounts,
+            transaction_history: transactions,
         }
     }
-}
-pub(crate) async fn run<S: Write>(
-    mut rx: Rx<EngineEvent>,
-    report_stream: S,
-) -> Result<(), EngineError> {
-    let mut engine = Engine::new();
-    /// Helper macro for decision logic.
-    /// There are 2 transaction categories, [deposit, withdrawal] and [dispute, resolve, chargeback]
-    /// Macro created to minimize repetition in code.
-    /// Type 1 inputs
-    /// + target => Target account
-    /// + tx => Transaction id
-    /// + amount => Amount
-    /// + method => What implemented method to use.
-    /// Type 2 inputs
-    /// + target => Target account
-    /// + tx => Transaction id
-    /// + [cond, is_dispute] =>
-    /// + method => What implemented method to use.
-    macro_rules! process_transaction {
-        // Transaction type 1 (deposit/withdrawal)
-        (transaction_type_1, $target:expr, $tx:expr, $amount:expr, $method:ident) => {
-            if engine.transaction_history.contains_key(&$tx) {
-                continue; // Tx already exists, do not re add it to history.
-            }
-            if let Some(amount) = $amount {
-                if let Err(_) = $target.$method(&amount) {
-                    continue; // Continue in case of error.
-                }
-                engine
-                    .transaction_history
-                    .insert($tx, History::new(amount, false));
-            }
-        };
-        // Transaction type 2 (dispute/resolve/chargeback)
-        (transaction_type_2, $target:expr, $tx:expr, [$cond:expr, $is_dispute:expr], $method:ident) => {
-            // There are quite many branches here ... probably would be a better idea to break out the macro to functions.
-            if let Some(transaction) = engine.transaction_history.get(&$tx) {
-                if transaction.dispute != $cond {
-                    continue; // Let's not go unnecessarily deep.
-                }
-
-                if let Some(amount) = Some(transaction.amount) {
-                    if let Err(_) = $target.$method(&amount) {
-                        continue; // Don't break on error.
-                    }
 
-                    engine
-                        .transaction_history
-                        .insert($tx, History::new(amount, $is_dispute));
-                }
+    /// Returns a copy of every account, ordered by client.
+    pub fn snapshot(&self) -> Vec<AccountSnapshot> {
+        let mut accounts: Vec<AccountSnapshot> = self
+            .account
+            .all()
+            .iter()
+            .map(AccountSnapshot::from)
+            .collect();
+        accounts.sort_by_key(|a| a.client);
+        accounts
+    }
+
+    /// Returns a copy of a single account.
+    pub fn account(&self, client: u16) -> Option<AccountSnapshot> {
+        self.account.get(client).as_ref().map(AccountSnapshot::from)
+    }
+
+    /// Returns a copy of a stored deposit or withdrawal, including its
+    /// dispute lifecycle state.
+    pub fn transaction(&self, tx: u32) -> Option<TransactionSnapshot> {
+        self.transaction_history.get(tx).map(|h| h.snapshot(tx))
+    }
+
+    /// Returns a copy of every stored deposit and withdrawal, ordered by id.
+    pub fn transactions(&self) -> Vec<TransactionSnapshot> {
+        let mut transactions: Vec<TransactionSnapshot> = self
+            .transaction_history
+            .all()
+            .iter()
+            .map(|(tx, h)| h.snapshot(*tx))
+            .collect();
+        transactions.sort_by_key(|t| t.tx);
+        transactions
+    }
+
+    /// Stores `accounts` and `transactions`, e.g. from a snapshot, replacing
+    /// any account or transaction with the same client or id.
+    pub fn load(
+        &mut self,
+        accounts: &[AccountSnapshot],
+        transactions: &[TransactionSnapshot],
+    ) -> Result<(), StoreError> {
+        for account in accounts {
+            self.account.insert(account.client, account.into());
+        }
+        for transaction in transactions {
+            self.transaction_history
+                .insert(transaction.tx, transaction.into());
+        }
+        self.account.commit()?;
+        self.transaction_history.commit()
+    }
+
+    /// Applies a single transaction, returning whether it was applied.
+    /// A rejected transaction does not change any state.
+    /// The writes of every transaction are committed to the stores, a failing
+    /// commit rejects the transaction with [`TransactionError::Store`].
+    pub fn apply(&mut self, transaction: Transaction) -> TxOutcome {
+        let processed = self.try_process(&transaction);
+        let committed = self
+            .account
+            .commit()
+            .and_then(|()| self.transaction_history.commit());
+        match (processed, committed) {
+            (_, Err(e)) => TxOutcome::Rejected(e.into()),
+            (Ok(()), Ok(())) => TxOutcome::Applied,
+            (Err(reason), Ok(())) => TxOutcome::Rejected(reason),
+        }
+    }
+
+    /// Writes every account as csv to `stream`.
+    /// Accounts with a negative available balance are logged.
+    pub fn report<S: Write>(&self, stream: S) -> Result<(), FileError> {
+        self.report_with_head(stream, None)
+    }
+
+    /// Same as [`Engine::report`], followed by the journal chain `head`.
+    pub fn report_with_head<S: Write>(
+        &self,
+        stream: S,
+        head: Option<&str>,
+    ) -> Result<(), FileError> {
+        // Sort keys for testing, avoid overhead for release build.
+        #[cfg(not(test))]
+        let accounts = self.account.all();
+
+        #[cfg(test)]
+        let accounts: Vec<Account> = self
+            .account
+            .all()
+            .into_iter()
+            .sorted_by_key(|a| a.client) // Sort by client for tests
+            .collect();
+        for account in accounts.iter().filter(|a| a.exposure() > Amount::ZERO) {
+            warn!(client = account.client, exposure = %account.exposure(), "Negative available balance");
+        }
+        csv_to_stdout(&accounts, head, stream)
+    }
+
+    fn try_process(&mut self, e: &Transaction) -> Result<(), TransactionError> {
+        let mut target = match self.account.get(e.client) {
+            Some(account) => account,
+            None => {
+                // Any transaction opens an account, even a rejected one.
+                let account = Account::new(e.client);
+                self.account.insert(e.client, account.clone());
+                account
             }
         };
+        self.process_on(&mut target, e)?;
+        self.account.insert(e.client, target);
+        Ok(())
     }
 
-    while let Some(event) = rx.receive.recv().await
-    // Blocking recv, could go for polling as well.
-    {
-        match event {
-            EngineEvent::Tx(e) => {
-                let target = engine
-                    .account
-                    .entry(e.client)
-                    .or_insert_with(|| Account::new(e.client));
-                match (&target.locked, &e.typename) {
-                    (true, _) => {
-                        continue;
-                    }
-                    (false, TransactionType::Deposit) => {
-                        process_transaction!(
-                            transaction_type_1,
-                            target,
-                            e.tx,
-                            e.amount,
-                            deposit
-                        );
-                    }
-                    (false, TransactionType::Withdrawal) => {
-                        process_transaction!(
-                            transaction_type_1,
-                            target,
-                            e.tx,
-                            e.amount,
-                            withdrawl
-                        );
-                    }
-                    (false, TransactionType::Dispute) => {
-                        process_transaction!(
-                            transaction_type_2,
-                            target,
-                            e.tx,
-                            [false, true],
-                            dispute
-                        );
-                    }
-                    (false, TransactionType::Resolve) => {
-                        process_transaction!(
-                            transaction_type_2,
-                            target,
-                            e.tx,
-                            [true, false],
-                            resolve
-                        );
-                    }
-                    (false, TransactionType::Chargeback) => {
-                        process_transaction!(
-                            transaction_type_2,
-                            target,
-                            e.tx,
-                            [true, false],
-                            chargeback
-                        );
+    /// Applies `e` to `target`, a copy of the account of `e.client`.
+    fn process_on(
+        &mut self,
+        target: &mut Account,
+        e: &Transaction,
+    ) -> Result<(), TransactionError> {
+        match (&target.locked, &e.typename) {
+            (true, _) => Err(AccountError::AccountLocked.into()),
+            (false, TransactionType::Deposit) => {
+                process_transaction!(
+                    transaction_type_1,
+                    self,
+                    target,
+                    e.client,
+                    e.tx,
+                    e.amount,
+                    e.typename,
+                    deposit
+                )
+            }
+            (false, TransactionType::Withdrawal) => {
+                process_transaction!(
+                    transaction_type_1,
+                    self,
+                    target,
+                    e.client,
+                    e.tx,
+                    e.amount,
+                    e.typename,
+                    withdrawl
+                )
+            }
+            (false, TransactionType::Dispute) => {
+                let dispute = match self.config.hold_policy {
+                    HoldPolicy::RequireFunds => Account::dispute,
+                    HoldPolicy::AllowNegative => {
+                        Account::dispute_allow_negative
                     }
-                }
+                };
+                process_transaction!(
+                    transaction_type_2,
+                    self,
+                    target,
+                    e.client,
+                    e.tx,
+                    &e.typename,
+                    [dispute, Account::dispute_withdrawal]
+                )
+            }
+            (false, TransactionType::Resolve) => {
+                process_transaction!(
+                    transaction_type_2,
+                    self,
+                    target,
+                    e.client,
+                    e.tx,
+                    &e.typename,
+                    [Account::resolve, Account::resolve_withdrawal]
+                )
             }
-            EngineEvent::Report() => {
-                // Sort keys for testing, avoid overhead for release build.
-                #[cfg(not(test))]
-                let accounts: Vec<&Account> = engine.account.values().collect();
-
-                #[cfg(test)]
-                let accounts: Vec<&Account> = engine
-                    .account
-                    .iter()
-                    .sorted_by_key(|(key, _)| *key) // Sort by key (u16) for tests
-                    .map(|(_, a)| a) // Extract account reference
-                    .collect();
-                csv_to_stdout(accounts, report_stream)?;
-                break;
+            (false, TransactionType::Chargeback) => {
+                process_transaction!(
+                    transaction_type_2,
+                    self,
+                    target,
+                    e.client,
+                    e.tx,
+                    &e.typename,
+                    [Account::chargeback, Account::chargeback_withdrawal]
+                )
             }
         }
     }
-    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Engine;
+    use crate::entities::policy::{DisputePolicy, EngineConfig, HoldPolicy};
+    use crate::entities::transaction::{
+        Transaction, TransactionType, TxOutcome, TxState,
+    };
+    use crate::errors::{AccountError, TransactionError};
+
+    fn transaction(
+        typename: TransactionType,
+        tx: u32,
+        amount: Option<&str>,
+    ) -> Transaction {
+        Transaction {
+            typename,
+            client: 1,
+            tx,
+            amount: amount.map(|a| a.parse().unwrap()),
+        }
+    }
+    #[test]
+    fn test_resolved_is_final() {
+        let mut engine = Engine::new(EngineConfig::default());
+        for event in [
+            transaction(TransactionType::Deposit, 1, Some("1")),
+            transaction(TransactionType::Dispute, 1, None),
+            transaction(TransactionType::Resolve, 1, None),
+        ] {
+            assert!(matches!(engine.apply(event), TxOutcome::Applied));
+        }
+        assert_eq!(
+            engine.transaction(1).map(|t| t.state),
+            Some(TxState::Resolved)
+        );
+        for typename in [
+            TransactionType::Dispute,
+            TransactionType::Resolve,
+            TransactionType::Chargeback,
+        ] {
+            assert!(matches!(
+                engine.apply(transaction(typename, 1, None)),
+                TxOutcome::Rejected(TransactionError::InvalidTransition {
+                    tx: 1,
+                    state: TxState::Resolved
+                })
+            ));
+        }
+        assert_eq!(
+            engine.transaction(1).map(|t| t.state),
+            Some(TxState::Resolved)
+        );
+    }
+    #[test]
+    fn test_chargeback_is_final() {
+        let mut engine = Engine::new(EngineConfig::default());
+        for event in [
+            transaction(TransactionType::Deposit, 1, Some("1")),
+            transaction(TransactionType::Deposit, 2, Some("1")),
+            transaction(TransactionType::Dispute, 1, None),
+            transaction(TransactionType::Chargeback, 1, None),
+        ] {
+            assert!(matches!(engine.apply(event), TxOutcome::Applied));
+        }
+        assert_eq!(
+            engine.transaction(1).map(|t| t.state),
+            Some(TxState::ChargedBack)
+        );
+        assert_eq!(
+            engine.transaction(2).map(|t| t.state),
+            Some(TxState::Processed)
+        );
+        assert_eq!(engine.transaction(3).map(|t| t.state), None);
+    }
+    fn balances(engine: &Engine) -> (String, String, String) {
+        let account = &engine.account[&1];
+        (
+            account.available.to_string(),
+            account.held.to_string(),
+            account.total.to_string(),
+        )
+    }
+    fn disputed_withdrawal(policy: DisputePolicy) -> (Engine, Vec<TxOutcome>) {
+        let mut engine = Engine::new(EngineConfig {
+            dispute_policy: policy,
+            ..Default::default()
+        });
+        let outcomes = [
+            transaction(TransactionType::Deposit, 1, Some("3")),
+            transaction(TransactionType::Withdrawal, 2, Some("1")),
+            transaction(TransactionType::Deposit, 3, Some("1")),
+            transaction(TransactionType::Dispute, 2, None),
+            transaction(TransactionType::Dispute, 3, None),
+        ]
+        .into_iter()
+        .map(|event| engine.apply(event))
+        .collect();
+        (engine, outcomes)
+    }
+    #[test]
+    fn test_policy_deposits_only() {
+        let (engine, outcomes) =
+            disputed_withdrawal(DisputePolicy::DepositsOnly);
+        assert!(matches!(
+            outcomes.as_slice(),
+            [
+                TxOutcome::Applied,
+                TxOutcome::Applied,
+                TxOutcome::Applied,
+                TxOutcome::Rejected(TransactionError::NotDisputable(2)),
+                TxOutcome::Applied
+            ]
+        ));
+        assert_eq!(balances(&engine), ("2".into(), "1".into(), "3".into()));
+    }
+    #[test]
+    fn test_policy_withdrawals_reverse_sign() {
+        let (engine, outcomes) =
+            disputed_withdrawal(DisputePolicy::WithdrawalsReverseSign);
+        assert!(matches!(
+            outcomes.as_slice(),
+            [
+                TxOutcome::Applied,
+                TxOutcome::Applied,
+                TxOutcome::Applied,
+                TxOutcome::Applied,
+                TxOutcome::Rejected(TransactionError::NotDisputable(3))
+            ]
+        ));
+        assert_eq!(balances(&engine), ("3".into(), "1".into(), "4".into()));
+    }
+    #[test]
+    fn test_policy_both() {
+        let (mut engine, outcomes) = disputed_withdrawal(DisputePolicy::Both);
+        assert!(outcomes.iter().all(|o| matches!(o, TxOutcome::Applied)));
+        assert_eq!(balances(&engine), ("2".into(), "2".into(), "4".into()));
+        let resolve = transaction(TransactionType::Resolve, 2, None);
+        assert!(matches!(engine.apply(resolve), TxOutcome::Applied));
+        assert_eq!(balances(&engine), ("2".into(), "1".into(), "3".into()));
+        let chargeback = transaction(TransactionType::Chargeback, 3, None);
+        assert!(matches!(engine.apply(chargeback), TxOutcome::Applied));
+        assert_eq!(balances(&engine), ("2".into(), "0".into(), "2".into()));
+    }
+    fn disputed_after_withdrawal(policy: HoldPolicy) -> (Engine, TxOutcome) {
+        let mut engine = Engine::new(EngineConfig {
+            hold_policy: policy,
+            ..Default::default()
+        });
+        for event in [
+            transaction(TransactionType::Deposit, 1, Some("3")),
+            transaction(TransactionType::Withdrawal, 2, Some("2")),
+        ] {
+            assert!(matches!(engine.apply(event), TxOutcome::Applied));
+        }
+        let outcome =
+            engine.apply(transaction(TransactionType::Dispute, 1, None));
+        (engine, outcome)
+    }
+    #[test]
+    fn test_hold_require_funds() {
+        let (engine, outcome) =
+            disputed_after_withdrawal(HoldPolicy::RequireFunds);
+        assert!(matches!(
+            outcome,
+            TxOutcome::Rejected(TransactionError::Account(
+                AccountError::InsufficientFunds
+            ))
+        ));
+        assert_eq!(balances(&engine), ("1".into(), "0".into(), "1".into()));
+    }
+    #[test]
+    fn test_hold_allow_negative() {
+        let (mut engine, outcome) =
+            disputed_after_withdrawal(HoldPolicy::AllowNegative);
+        assert!(matches!(outcome, TxOutcome::Applied));
+        assert_eq!(balances(&engine), ("-2".into(), "3".into(), "1".into()));
+        assert_eq!(engine.account[&1].exposure().to_string(), "2");
+        assert_eq!(engine.account(1).unwrap().exposure().to_string(), "2");
+        let chargeback = transaction(TransactionType::Chargeback, 1, None);
+        assert!(matches!(engine.apply(chargeback), TxOutcome::Applied));
+        assert_eq!(balances(&engine), ("-2".into(), "0".into(), "-2".into()));
+    }
+    #[test]
+    fn test_non_positive_amount() {
+        let mut engine = Engine::new(EngineConfig::default());
+        let deposit = transaction(TransactionType::Deposit, 1, Some("1"));
+        assert!(matches!(engine.apply(deposit), TxOutcome::Applied));
+        for (tx, event) in [
+            (2, transaction(TransactionType::Withdrawal, 2, Some("-100"))),
+            (3, transaction(TransactionType::Deposit, 3, Some("-5"))),
+            (4, transaction(TransactionType::Deposit, 4, Some("0"))),
+        ] {
+            assert!(matches!(
+                engine.apply(event),
+                TxOutcome::Rejected(TransactionError::NonPositiveAmount(t)) if t == tx
+            ));
+            assert_eq!(engine.transaction(tx), None);
+        }
+        assert_eq!(balances(&engine), ("1".into(), "0".into(), "1".into()));
+    }
+    #[test]
+    fn test_report() {
+        let mut engine = Engine::new(EngineConfig::default());
+        for client in [2, 1] {
+            let deposit = Transaction::deposit(
+                client,
+                client.into(),
+                "1".parse().unwrap(),
+            );
+            assert!(matches!(engine.apply(deposit), TxOutcome::Applied));
+        }
+        let mut report = vec![];
+        assert!(engine.report(&mut report).is_ok());
+        assert_eq!(
+            String::from_utf8(report).unwrap(),
+            "client,available,held,total,locked\n1,1,0,1,false\n2,1,0,1,false\n"
+        );
+    }
 }
@@ -1,89 +1,179 @@
 //! Account specific data structs and implementations
-use serde::{Serialize, Serializer};
+use serde::{Deserialize, Serialize};
 
+use super::amount::Amount;
 use crate::errors::AccountError;
-#[derive(Serialize, Default, Debug)]
-/// Account data.
-pub(crate) struct Account {
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+/// Account data, see [`AccountStore`](crate::AccountStore).
+pub struct Account {
     /// The owner of the account.
     pub(crate) client: u16,
     /// Total funds available for the account.
-    #[serde(serialize_with = "serialize_round_float")]
-    pub(crate) available: f64,
+    pub(crate) available: Amount,
     /// The total funds that are held for dispute.
-    #[serde(serialize_with = "serialize_round_float")]
-    pub(crate) held: f64,
+    pub(crate) held: Amount,
     /// Total funds that are available or held.
-    #[serde(serialize_with = "serialize_round_float")]
-    pub(crate) total: f64,
+    pub(crate) total: Amount,
     /// Whether the account is locked.
     pub(crate) locked: bool,
 }
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+/// Point in time copy of an account.
+pub struct AccountSnapshot {
+    /// The owner of the account.
+    pub client: u16,
+    /// Total funds available for the account.
+    pub available: Amount,
+    /// The total funds that are held for dispute.
+    pub held: Amount,
+    /// Total funds that are available or held.
+    pub total: Amount,
+    /// Whether the account is locked.
+    pub locked: bool,
+}
+impl AccountSnapshot {
+    /// Funds owed by the client, i.e. how far available is below zero,
+    /// only possible with [`HoldPolicy::AllowNegative`](crate::HoldPolicy).
+    #[must_use]
+    pub fn exposure(&self) -> Amount {
+        self.available.saturating_neg().max(Amount::ZERO)
+    }
+}
+impl From<&AccountSnapshot> for Account {
+    fn from(snapshot: &AccountSnapshot) -> Self {
+        Account {
+            client: snapshot.client,
+            available: snapshot.available,
+            held: snapshot.held,
+            total: snapshot.total,
+            locked: snapshot.locked,
+        }
+    }
+}
+impl From<&Account> for AccountSnapshot {
+    fn from(account: &Account) -> Self {
+        AccountSnapshot {
+            client: account.client,
+            available: account.available,
+            held: account.held,
+            total: account.total,
+            locked: account.locked,
+        }
+    }
+}
 /// Creates a member function to the Account struct.
 /// The function created with the macro contains code for operating on the account balances.
 /// Inputs:
 /// $name = name of function to create, e.g. deposit
-/// (target, operation) = target on self to do operation on, e.g. (total, +=) becomes `self.total +=`
+/// (target, operation) = target on self to do operation on, e.g. (total, checked_add) becomes `self.total.checked_add`
 /// [self, amount] = These are here to make it possible to use inside of the assert function, please use [self, amount]
-/// cond = condition to be true in order for operations to go through.
+/// cond => err = condition to be true in order for operations to go through, otherwise `err` is returned.
+/// A balance that would overflow is refused with `AccountError::Overflow`, leaving every balance unchanged.
 /// asserts = expands to a function body, that has access to self and amount. Anything can be placed here but the intention is for assertions.
 /// For example
 /// ``` ignore
-/// modify_account_balance_fn!(dispute, ((held, +=), (available, -=)),[self, amount], self.available - amount >= 0_f64, {});
+/// modify_account_balance_fn!(dispute, ((held, checked_add), (available, checked_sub)),[self, amount], self.available >= *amount => AccountError::InsufficientFunds, {});
 /// ```
 /// expands to
 /// ``` ignore
-/// pub(crate) fn dispute(&mut self, amount: &f64) -> Result<(), AccountError> {
+/// pub(crate) fn dispute(&mut self, amount: &Amount) -> Result<(), AccountError> {
 /// {}
-/// if !self.locked && self.available - amount >= 0_f64 {
-///    self.held += amount;
-///    self.available -= amount;
+/// if self.locked {
+///    return Err(AccountError::AccountLocked);
+/// }
+/// if !(self.available >= *amount) {
+///    return Err(AccountError::InsufficientFunds);
 /// }
+/// let held = self.held.checked_add(*amount).map_err(|_| AccountError::Overflow)?;
+/// let available = self.available.checked_sub(*amount).map_err(|_| AccountError::Overflow)?;
+/// self.held = held;
+/// self.available = available;
 /// Ok(())
 /// }
 /// ```
 macro_rules! modify_account_balance_fn {
-            ($name:ident, ($( ($target:ident, $operation:tt) ),*), [$self:ident, $amount:ident], $cond:expr, $asserts:block) => {
+            ($name:ident, ($( ($target:ident, $operation:ident) ),*), [$self:ident, $amount:ident], $cond:expr => $err:expr, $asserts:block) => {
                 pub(crate) fn $name(
                     &mut $self,
-                    $amount: &f64
+                    $amount: &Amount
                 ) -> Result<(), AccountError> {
                     // Perform assertions or checks before continuing with the operation
                     $asserts
-                    // Check the condition and whether the account is locked
-                    if !$self.locked && $cond {
-                        // Loop through each target and perform the corresponding operation
-                        $(
-                            $self.$target $operation $amount;
-                        )*
-
-                        // Optionally, include a return statement with Ok
+                    // Check whether the account is locked and the condition
+                    if $self.locked {
+                        return Err(AccountError::AccountLocked);
                     }
+                    if !$cond {
+                        return Err($err);
+                    }
+                    // Compute every new balance before changing any of them
+                    $(
+                        let $target = $self.$target.$operation(*$amount).map_err(|_| AccountError::Overflow)?;
+                    )*
+                    $(
+                        $self.$target = $target;
+                    )*
 
                     Ok(())
                 }
             };
         }
 impl Account {
-    modify_account_balance_fn!(deposit, ((total, +=), (available, +=)), [self, amount], true, {
-        assert!(self.total + amount <= f64::MAX, "Unable to add {:?} to account, as the total will overflow.", &amount);
-        assert!(self.total + self.available <= f64::MAX, "Unable to add {:?} to account, as the total will overflow.", &amount);
-    });
-    modify_account_balance_fn!(withdrawl, ((total, -=), (available, -=)),[self, amount], self.available - amount >= 0_f64, {});
-    modify_account_balance_fn!(dispute, ((held, +=), (available, -=)),[self, amount], self.available - amount >= 0_f64, {});
-    modify_account_balance_fn!(resolve, ((held, -=), (available, +=)),[self, amount], self.held - amount >= 0_f64, {});
+    modify_account_balance_fn!(deposit, ((total, checked_add), (available, checked_add)), [self, amount], self.total.checked_add(*amount).is_ok() && self.available.checked_add(*amount).is_ok() => AccountError::Overflow, {});
+    modify_account_balance_fn!(withdrawl, ((total, checked_sub), (available, checked_sub)),[self, amount], self.available >= *amount => AccountError::InsufficientFunds, {});
+    modify_account_balance_fn!(dispute, ((held, checked_add), (available, checked_sub)),[self, amount], self.available >= *amount => AccountError::InsufficientFunds, {});
+    modify_account_balance_fn!(dispute_allow_negative, ((held, checked_add), (available, checked_sub)),[self, amount], self.held.checked_add(*amount).is_ok() && self.available.checked_sub(*amount).is_ok() => AccountError::Overflow, {});
+    modify_account_balance_fn!(resolve, ((held, checked_sub), (available, checked_add)),[self, amount], self.held >= *amount => AccountError::InsufficientHeld, {});
+    modify_account_balance_fn!(dispute_withdrawal, ((held, checked_add), (total, checked_add)),[self, amount], self.total.checked_add(*amount).is_ok() && self.held.checked_add(*amount).is_ok() => AccountError::Overflow, {});
+    modify_account_balance_fn!(resolve_withdrawal, ((held, checked_sub), (total, checked_sub)),[self, amount], self.held >= *amount => AccountError::InsufficientHeld, {});
     pub(crate) fn chargeback(
         &mut self,
-        amount: &f64,
+        amount: &Amount,
+    ) -> Result<(), AccountError> {
+        if self.locked {
+            return Err(AccountError::AccountLocked);
+        }
+        if self.held < *amount {
+            return Err(AccountError::InsufficientHeld);
+        }
+        let total = self.total.checked_sub(*amount);
+        let held = self.held.checked_sub(*amount);
+        let (Ok(total), Ok(held)) = (total, held) else {
+            return Err(AccountError::Overflow);
+        };
+        self.locked = true;
+        self.total = total;
+        self.held = held;
+        Ok(())
+    }
+    /// Reverses a disputed withdrawal, the held amount is released to available.
+    pub(crate) fn chargeback_withdrawal(
+        &mut self,
+        amount: &Amount,
     ) -> Result<(), AccountError> {
-        if self.held - amount >= 0_f64 {
-            self.locked = true;
-            self.total -= amount;
-            self.held -= amount;
+        if self.locked {
+            return Err(AccountError::AccountLocked);
+        }
+        if self.held < *amount {
+            return Err(AccountError::InsufficientHeld);
         }
+        let held = self.held.checked_sub(*amount);
+        let available = self.available.checked_add(*amount);
+        let (Ok(held), Ok(available)) = (held, available) else {
+            return Err(AccountError::Overflow);
+        };
+        self.locked = true;
+        self.held = held;
+        self.available = available;
         Ok(())
     }
 
+    /// Funds owed by the client, see [`AccountSnapshot::exposure`].
+    #[must_use]
+    pub fn exposure(&self) -> Amount {
+        self.available.saturating_neg().max(Amount::ZERO)
+    }
+
     pub(crate) fn new(client_id: u16) -> Self {
         Self {
             client: client_id,
@@ -91,122 +181,204 @@ impl Account {
         }
     }
 }
-/// Serializes a float by rounding it to 4 decimals.
-/// Trims away 0s and `.`, e.g 1.0000 => 1 while 1.5000 => 1.5
-/// Precision requirement.
-fn serialize_round_float<S>(f: &f64, s: S) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-{
-    s.serialize_str(
-        format!("{:.4}", f)
-            .as_str()
-            .trim_end_matches('0')
-            .trim_end_matches('.'),
-    )
-}
 #[cfg(test)]
 mod tests {
     use super::Account;
+    use crate::entities::amount::Amount;
+    use crate::errors::AccountError;
 
     fn create_account() -> Account {
         Account::new(1)
     }
+    fn amount(value: i64) -> Amount {
+        value.to_string().parse().unwrap()
+    }
     #[test]
     fn test_deposit() {
         let mut account = create_account();
 
-        let res = account.deposit(&1_f64);
+        let res = account.deposit(&amount(1));
         assert!(res.is_ok());
-        assert_eq!(account.available, 1_f64);
-        assert_eq!(account.held, 0_f64);
-        assert_eq!(account.total, 1_f64);
+        assert_eq!(account.available, amount(1));
+        assert_eq!(account.held, amount(0));
+        assert_eq!(account.total, amount(1));
     }
     #[test]
     fn test_withdrawl() {
         let mut account = create_account();
         {
-            let res = account.deposit(&1_f64);
+            let res = account.deposit(&amount(1));
             assert!(res.is_ok());
-            assert_eq!(account.available, 1_f64);
-            assert_eq!(account.held, 0_f64);
-            assert_eq!(account.total, 1_f64);
+            assert_eq!(account.available, amount(1));
+            assert_eq!(account.held, amount(0));
+            assert_eq!(account.total, amount(1));
         }
         {
-            let res = account.withdrawl(&1_f64);
+            let res = account.withdrawl(&amount(1));
             assert!(res.is_ok());
-            assert_eq!(account.available, 0_f64);
-            assert_eq!(account.held, 0_f64);
-            assert_eq!(account.total, 0_f64);
+            assert_eq!(account.available, amount(0));
+            assert_eq!(account.held, amount(0));
+            assert_eq!(account.total, amount(0));
         }
     }
     #[test]
     fn test_dispute() {
         let mut account = create_account();
         {
-            let res = account.deposit(&1_f64);
+            let res = account.deposit(&amount(1));
             assert!(res.is_ok());
-            assert_eq!(account.available, 1_f64);
-            assert_eq!(account.held, 0_f64);
-            assert_eq!(account.total, 1_f64);
+            assert_eq!(account.available, amount(1));
+            assert_eq!(account.held, amount(0));
+            assert_eq!(account.total, amount(1));
         }
         {
-            let res = account.dispute(&1_f64);
+            let res = account.dispute(&amount(1));
             assert!(res.is_ok());
-            assert_eq!(account.available, 0_f64);
-            assert_eq!(account.held, 1_f64);
-            assert_eq!(account.total, 1_f64);
+            assert_eq!(account.available, amount(0));
+            assert_eq!(account.held, amount(1));
+            assert_eq!(account.total, amount(1));
         }
     }
     #[test]
     fn test_resolve() {
         let mut account = create_account();
         {
-            let res = account.deposit(&1_f64);
+            let res = account.deposit(&amount(1));
             assert!(res.is_ok());
-            assert_eq!(account.available, 1_f64);
-            assert_eq!(account.held, 0_f64);
-            assert_eq!(account.total, 1_f64);
+            assert_eq!(account.available, amount(1));
+            assert_eq!(account.held, amount(0));
+            assert_eq!(account.total, amount(1));
         }
         {
-            let res = account.dispute(&1_f64);
+            let res = account.dispute(&amount(1));
             assert!(res.is_ok());
-            assert_eq!(account.available, 0_f64);
-            assert_eq!(account.held, 1_f64);
-            assert_eq!(account.total, 1_f64);
+            assert_eq!(account.available, amount(0));
+            assert_eq!(account.held, amount(1));
+            assert_eq!(account.total, amount(1));
         }
         {
-            let res = account.resolve(&1_f64);
+            let res = account.resolve(&amount(1));
             assert!(res.is_ok());
-            assert_eq!(account.available, 1_f64);
-            assert_eq!(account.held, 0_f64);
-            assert_eq!(account.total, 1_f64);
+            assert_eq!(account.available, amount(1));
+            assert_eq!(account.held, amount(0));
+            assert_eq!(account.total, amount(1));
         }
     }
     #[test]
     fn test_chargeback() {
         let mut account = create_account();
         {
-            let res = account.deposit(&1_f64);
+            let res = account.deposit(&amount(1));
             assert!(res.is_ok());
-            assert_eq!(account.available, 1_f64);
-            assert_eq!(account.held, 0_f64);
-            assert_eq!(account.total, 1_f64);
+            assert_eq!(account.available, amount(1));
+            assert_eq!(account.held, amount(0));
+            assert_eq!(account.total, amount(1));
         }
         {
-            let res = account.dispute(&1_f64);
+            let res = account.dispute(&amount(1));
             assert!(res.is_ok());
-            assert_eq!(account.available, 0_f64);
-            assert_eq!(account.held, 1_f64);
-            assert_eq!(account.total, 1_f64);
+            assert_eq!(account.available, amount(0));
+            assert_eq!(account.held, amount(1));
+            assert_eq!(account.total, amount(1));
         }
         {
-            let res = account.chargeback(&1_f64);
+            let res = account.chargeback(&amount(1));
             assert!(res.is_ok());
-            assert_eq!(account.available, 0_f64);
-            assert_eq!(account.held, 0_f64);
-            assert_eq!(account.total, 0_f64);
+            assert_eq!(account.available, amount(0));
+            assert_eq!(account.held, amount(0));
+            assert_eq!(account.total, amount(0));
             assert!(account.locked);
         }
     }
+    #[test]
+    fn test_withdrawal_dispute() {
+        let mut account = create_account();
+        assert!(account.deposit(&amount(3)).is_ok());
+        assert!(account.withdrawl(&amount(2)).is_ok());
+        {
+            let res = account.dispute_withdrawal(&amount(2));
+            assert!(res.is_ok());
+            assert_eq!(account.available, amount(1));
+            assert_eq!(account.held, amount(2));
+            assert_eq!(account.total, amount(3));
+        }
+        {
+            let res = account.resolve_withdrawal(&amount(2));
+            assert!(res.is_ok());
+            assert_eq!(account.available, amount(1));
+            assert_eq!(account.held, amount(0));
+            assert_eq!(account.total, amount(1));
+        }
+        {
+            assert!(account.dispute_withdrawal(&amount(1)).is_ok());
+            let res = account.chargeback_withdrawal(&amount(1));
+            assert!(res.is_ok());
+            assert_eq!(account.available, amount(2));
+            assert_eq!(account.held, amount(0));
+            assert_eq!(account.total, amount(2));
+            assert!(account.locked);
+        }
+    }
+    #[test]
+    fn test_insufficient_funds() {
+        let mut account = create_account();
+        assert!(account.deposit(&amount(1)).is_ok());
+        assert!(matches!(
+            account.withdrawl(&amount(2)),
+            Err(AccountError::InsufficientFunds)
+        ));
+        assert!(matches!(
+            account.dispute(&amount(2)),
+            Err(AccountError::InsufficientFunds)
+        ));
+        assert!(matches!(
+            account.resolve(&amount(1)),
+            Err(AccountError::InsufficientHeld)
+        ));
+        assert!(matches!(
+            account.chargeback(&amount(1)),
+            Err(AccountError::InsufficientHeld)
+        ));
+        assert_eq!(account.available, amount(1));
+        assert_eq!(account.total, amount(1));
+    }
+    #[test]
+    fn test_locked() {
+        let mut account = create_account();
+        assert!(account.deposit(&amount(1)).is_ok());
+        assert!(account.dispute(&amount(1)).is_ok());
+        assert!(account.chargeback(&amount(1)).is_ok());
+        assert!(matches!(
+            account.deposit(&amount(1)),
+            Err(AccountError::AccountLocked)
+        ));
+        assert_eq!(account.total, amount(0));
+    }
+    #[test]
+    fn test_overflow() {
+        let mut account = create_account();
+        let max = "922337203685477".parse().unwrap();
+        assert!(account.deposit(&max).is_ok());
+        assert!(matches!(
+            account.deposit(&amount(1)),
+            Err(AccountError::Overflow)
+        ));
+        assert_eq!(account.total, max);
+        // Releasing held funds can not overflow available either.
+        let mut account = Account {
+            available: Amount::from_units(i64::MAX),
+            held: amount(1),
+            ..create_account()
+        };
+        assert!(matches!(
+            account.resolve(&amount(1)),
+            Err(AccountError::Overflow)
+        ));
+        assert!(matches!(
+            account.chargeback_withdrawal(&amount(1)),
+            Err(AccountError::Overflow)
+        ));
+        assert_eq!(account.held, amount(1));
+        assert!(!account.locked);
+    }
 }
@@ -0,0 +1,239 @@
+//! Fixed-point monetary amount.
+//!
+//! Balances and transaction amounts are stored as an integer number of
+//! ten-thousandths, i.e. four implied decimal places, so arithmetic is exact.
+use std::fmt;
+use std::str::FromStr;
+
+use serde::de::{Error as DeError, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::errors::AmountError;
+
+/// Number of implied decimal places.
+const PRECISION: usize = 4;
+/// Units per whole, 10^PRECISION.
+const SCALE: i64 = 10_000;
+
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// An exact amount with four decimal places, backed by an `i64`.
+pub struct Amount(i64);
+
+impl Amount {
+    /// The zero amount.
+    pub(crate) const ZERO: Amount = Amount(0);
+
+    /// Creates an amount from ten-thousandths, e.g. `15_000` is `1.5`.
+    #[must_use]
+    pub const fn from_units(units: i64) -> Self {
+        Amount(units)
+    }
+
+    /// Returns the amount in ten-thousandths.
+    #[must_use]
+    pub const fn units(&self) -> i64 {
+        self.0
+    }
+
+    /// Checked addition, fails with [`AmountError::Overflow`].
+    pub(crate) fn checked_add(
+        self,
+        rhs: Amount,
+    ) -> Result<Amount, AmountError> {
+        self.0
+            .checked_add(rhs.0)
+            .map(Amount)
+            .ok_or_else(|| AmountError::Overflow(format!("{self} + {rhs}")))
+    }
+
+    /// Checked subtraction, fails with [`AmountError::Overflow`].
+    pub(crate) fn checked_sub(
+        self,
+        rhs: Amount,
+    ) -> Result<Amount, AmountError> {
+        self.0
+            .checked_sub(rhs.0)
+            .map(Amount)
+            .ok_or_else(|| AmountError::Overflow(format!("{self} - {rhs}")))
+    }
+
+    /// Negation, the lowest amount has no positive counterpart and becomes
+    /// the highest.
+    #[must_use]
+    pub(crate) const fn saturating_neg(self) -> Amount {
+        Amount(self.0.saturating_neg())
+    }
+}
+
+/// Parses a decimal string, e.g. `1`, `-0.5`, `0.` or `.25`.
+/// Fractional digits beyond the fourth must be zero, otherwise the input is
+/// rejected rather than rounded.
+/// Signed amounts exist, balances may be negative, so transaction amounts
+/// parse with a sign too. The engine refuses deposits and withdrawals that
+/// are not positive, see [`TransactionError::NonPositiveAmount`].
+///
+/// [`TransactionError::NonPositiveAmount`]: crate::errors::TransactionError::NonPositiveAmount
+impl FromStr for Amount {
+    type Err = AmountError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let input = s.trim();
+        let (negative, digits) = match input.as_bytes().first() {
+            Some(b'-') => (true, &input[1..]),
+            Some(b'+') => (false, &input[1..]),
+            _ => (false, input),
+        };
+        let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        let is_digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+        if (whole.is_empty() && fraction.is_empty())
+            || !is_digits(whole)
+            || !is_digits(fraction)
+        {
+            return Err(AmountError::Invalid(s.to_string()));
+        }
+        if fraction.len() > PRECISION
+            && fraction[PRECISION..].bytes().any(|b| b != b'0')
+        {
+            return Err(AmountError::Precision(s.to_string()));
+        }
+
+        let whole_units = if whole.is_empty() {
+            0
+        } else {
+            whole
+                .parse::<i64>()
+                .ok()
+                .and_then(|w| w.checked_mul(SCALE))
+                .ok_or_else(|| AmountError::Overflow(s.to_string()))?
+        };
+        // Right pad to exactly PRECISION digits, e.g. `5` => `5000`.
+        let fraction_units = fraction
+            .bytes()
+            .chain(std::iter::repeat(b'0'))
+            .take(PRECISION)
+            .fold(0_i64, |acc, b| acc * 10 + i64::from(b - b'0'));
+        let units = whole_units
+            .checked_add(fraction_units)
+            .ok_or_else(|| AmountError::Overflow(s.to_string()))?;
+        Ok(Amount(if negative { -units } else { units }))
+    }
+}
+
+/// Formats with at most four decimals, trimming trailing zeros and `.`,
+/// e.g 1.0000 => 1 while 1.5000 => 1.5
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let abs = self.0.unsigned_abs();
+        let scale = SCALE.unsigned_abs();
+        let (whole, fraction) = (abs / scale, abs % scale);
+        if fraction == 0 {
+            return write!(f, "{sign}{whole}");
+        }
+        let fraction = format!("{fraction:0width$}", width = PRECISION);
+        write!(f, "{sign}{whole}.{}", fraction.trim_end_matches('0'))
+    }
+}
+
+impl Serialize for Amount {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        s.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D>(d: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        /// Visitor parsing amounts from their string representation.
+        struct AmountVisitor;
+        impl Visitor<'_> for AmountVisitor {
+            type Value = Amount;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a decimal amount with at most four decimals")
+            }
+
+            fn visit_str<E: DeError>(self, v: &str) -> Result<Amount, E> {
+                v.parse().map_err(E::custom)
+            }
+        }
+        d.deserialize_str(AmountVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Amount;
+    use crate::errors::AmountError;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("1".parse::<Amount>().unwrap(), Amount(10_000));
+        assert_eq!("1.5".parse::<Amount>().unwrap(), Amount(15_000));
+        assert_eq!("0.0001".parse::<Amount>().unwrap(), Amount(1));
+        assert_eq!("0.".parse::<Amount>().unwrap(), Amount::default());
+        assert_eq!(".25".parse::<Amount>().unwrap(), Amount(2_500));
+        assert_eq!("-0.5".parse::<Amount>().unwrap(), Amount(-5_000));
+        assert_eq!("0.10000".parse::<Amount>().unwrap(), Amount(1_000));
+    }
+    #[test]
+    fn test_parse_rejects() {
+        assert!(matches!(
+            "0.12345".parse::<Amount>(),
+            Err(AmountError::Precision(_))
+        ));
+        assert!(matches!("".parse::<Amount>(), Err(AmountError::Invalid(_))));
+        assert!(matches!(
+            ".".parse::<Amount>(),
+            Err(AmountError::Invalid(_))
+        ));
+        assert!(matches!(
+            "1e3".parse::<Amount>(),
+            Err(AmountError::Invalid(_))
+        ));
+        assert!(matches!(
+            "1.2.3".parse::<Amount>(),
+            Err(AmountError::Invalid(_))
+        ));
+        assert!(matches!(
+            "99999999999999999999".parse::<Amount>(),
+            Err(AmountError::Overflow(_))
+        ));
+    }
+    #[test]
+    fn test_display() {
+        assert_eq!(Amount(10_000).to_string(), "1");
+        assert_eq!(Amount(15_000).to_string(), "1.5");
+        assert_eq!(Amount(1_234).to_string(), "0.1234");
+        assert_eq!(Amount(-200).to_string(), "-0.02");
+        assert_eq!(Amount::default().to_string(), "0");
+    }
+    #[test]
+    fn test_checked() {
+        let max = Amount(i64::MAX);
+        assert!(matches!(
+            max.checked_add(Amount(1)),
+            Err(AmountError::Overflow(_))
+        ));
+        assert!(matches!(
+            Amount(i64::MIN).checked_sub(Amount(1)),
+            Err(AmountError::Overflow(_))
+        ));
+        assert_eq!(max.checked_sub(Amount(1)).unwrap(), Amount(i64::MAX - 1));
+        assert_eq!(Amount(i64::MIN).saturating_neg(), max);
+    }
+    #[test]
+    fn test_sum_reconciles() {
+        let step = "0.0001".parse::<Amount>().unwrap();
+        let mut total = Amount::default();
+        for _ in 0..1_000_000 {
+            total = total.checked_add(step).unwrap();
+        }
+        assert_eq!(total, "100".parse::<Amount>().unwrap());
+    }
+}
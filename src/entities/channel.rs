@@ -6,6 +6,11 @@ use tokio::sync::mpsc::{channel, Receiver, Sender};
 
 /// An Tx struct is used to send to a channel.
 pub struct Tx<E>(pub Sender<E>);
+impl<E> Clone for Tx<E> {
+    fn clone(&self) -> Self {
+        Tx(self.0.clone())
+    }
+}
 /// An Rx struct is used on the channel to receive.
 ///
 /// It contains a `Receiver` that is used to receive events.
@@ -20,6 +25,13 @@ pub(crate) struct Rx<E> {
 /// This function is must_use.
 #[must_use]
 pub(crate) fn create_engine_channel() -> (Tx<EngineEvent>, Rx<EngineEvent>) {
+    create_channel()
+}
+
+/// Create a new multi-producer, single-consumer channel of any event type.
+/// This function is must_use.
+#[must_use]
+pub(crate) fn create_channel<E>() -> (Tx<E>, Rx<E>) {
     // Outgoing MQTT queue through multi-producer, single-consumer channel. Many values can be sent.
     let (transmit, recv) = channel(100); // usize ...
     assert!(!transmit.is_closed());
@@ -1,12 +1,53 @@
 pub(crate) mod account;
+pub(crate) mod amount;
+#[cfg(feature = "runtime")]
 pub(crate) mod channel;
+pub(crate) mod policy;
+#[cfg(feature = "runtime")]
+pub(crate) mod rejection;
 pub(crate) mod transaction;
 
+#[cfg(feature = "runtime")]
+use crate::errors::FileError;
+#[cfg(feature = "runtime")]
+use account::AccountSnapshot;
+#[cfg(feature = "runtime")]
+use tokio::sync::oneshot;
+#[cfg(feature = "runtime")]
+use transaction::TransactionSnapshot;
+
+#[cfg(feature = "runtime")]
 #[derive(Debug)]
 /// An enum that defines the different types of events that can be utilized the channel.
 pub enum EngineEvent {
     /// Transaction Events
     Tx(transaction::Transaction),
-    /// Report Events
+    /// Report Events, writes the account report, the engine keeps running.
     Report(),
+    /// Replies with the current state of all accounts, ordered by client.
+    Snapshot(oneshot::Sender<Vec<AccountSnapshot>>),
+    /// Replies with the current state of `client`, `None` if it has no account.
+    GetAccount {
+        /// The client to look up.
+        client: u16,
+        /// Where to send the account.
+        reply: oneshot::Sender<Option<AccountSnapshot>>,
+    },
+    /// Replies with the stored deposit or withdrawal `tx`, `None` if unknown.
+    GetTransaction {
+        /// The transaction to look up.
+        tx: u32,
+        /// Where to send the transaction.
+        reply: oneshot::Sender<Option<TransactionSnapshot>>,
+    },
+    /// Writes a snapshot of the full engine state, accounts and transaction
+    /// history, to `path`, see [`StateSnapshot`](crate::StateSnapshot).
+    WriteSnapshot {
+        /// Where to write the snapshot.
+        path: String,
+        /// Whether the snapshot was written.
+        reply: oneshot::Sender<Result<(), FileError>>,
+    },
+    /// Stops the engine once every event before it is processed.
+    Shutdown(),
 }
@@ -0,0 +1,54 @@
+//! Engine configuration and policies.
+use super::transaction::TransactionType;
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+/// Which transactions may be disputed, and how.
+///
+/// A disputed deposit moves the amount from available to held.
+/// A disputed withdrawal is treated with reverse sign: the withdrawn amount is
+/// held (and added to total) until resolved, a resolve removes it again and a
+/// chargeback releases it to available and locks the account.
+pub enum DisputePolicy {
+    /// Only deposits can be disputed.
+    #[default]
+    DepositsOnly,
+    /// Only withdrawals can be disputed, with reverse sign.
+    WithdrawalsReverseSign,
+    /// Deposits and withdrawals (with reverse sign) can be disputed.
+    Both,
+}
+impl DisputePolicy {
+    /// Whether a transaction of type `kind` may be disputed.
+    pub(crate) fn allows(&self, kind: &TransactionType) -> bool {
+        matches!(
+            (self, kind),
+            (
+                DisputePolicy::DepositsOnly | DisputePolicy::Both,
+                TransactionType::Deposit
+            ) | (
+                DisputePolicy::WithdrawalsReverseSign | DisputePolicy::Both,
+                TransactionType::Withdrawal
+            )
+        )
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+/// How a dispute is held when the available funds do not cover it.
+pub enum HoldPolicy {
+    /// The dispute is rejected if available funds are insufficient.
+    #[default]
+    RequireFunds,
+    /// The full amount is always held, available may become negative.
+    /// Negative available balances are reported as exposure.
+    AllowNegative,
+}
+
+#[derive(Default, Debug, Clone, Copy)]
+/// Payment engine configuration.
+pub struct EngineConfig {
+    /// Which transactions may be disputed.
+    pub dispute_policy: DisputePolicy,
+    /// Whether disputes may drive available funds negative.
+    pub hold_policy: HoldPolicy,
+}
@@ -0,0 +1,36 @@
+//! Rejected transaction records, written to the optional rejection output.
+use serde::Serialize;
+
+/// Reason code for rows that could not be parsed.
+pub(crate) const MALFORMED: &str = "malformed";
+
+#[derive(Debug)]
+/// The original input row of a transaction.
+pub(crate) struct Row {
+    /// Line number of the row in the input, the header is line 1.
+    pub(crate) line: u64,
+    /// The row as read, without its line ending.
+    pub(crate) raw: String,
+    /// Whether the row could not be parsed, and was never sent to the engine.
+    pub(crate) malformed: bool,
+}
+impl Row {
+    pub(crate) fn new(line: u64, raw: String, malformed: bool) -> Self {
+        Row {
+            line,
+            raw,
+            malformed,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+/// A transaction that was not applied.
+pub(crate) struct Rejection<'a> {
+    /// Line number of the row in the input.
+    pub(crate) line: u64,
+    /// Machine-readable reason code.
+    pub(crate) reason: &'static str,
+    /// The original row.
+    pub(crate) row: &'a str,
+}
@@ -1,11 +1,14 @@
 //! Transaction related data structs and operations.
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Deserialize, Debug)]
+use super::amount::Amount;
+use crate::errors::TransactionError;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 /// Input transactions.
 pub struct Transaction {
-    #[serde(rename(deserialize = "type"))]
+    #[serde(rename = "type")]
     /// Type of the transaction.
     pub(crate) typename: TransactionType,
     /// The client that performed the transaction
@@ -13,16 +16,195 @@ pub struct Transaction {
     /// Unique transaction ID.
     pub(crate) tx: u32,
     /// Optional amount for the transaction.
-    pub(crate) amount: Option<f64>,
+    pub(crate) amount: Option<Amount>,
 }
 
-#[derive(Deserialize, Debug)]
+/// Creates public constructors for transactions with an amount.
+macro_rules! amount_transaction_fn {
+    ($name:ident, $typename:ident) => {
+        #[doc = concat!("Creates a `", stringify!($name), "` of `amount` for `client`.")]
+        #[must_use]
+        pub fn $name(client: u16, tx: u32, amount: Amount) -> Self {
+            Transaction {
+                typename: TransactionType::$typename,
+                client,
+                tx,
+                amount: Some(amount),
+            }
+        }
+    };
+}
+/// Creates public constructors for transactions referring to an earlier `tx`.
+macro_rules! reference_transaction_fn {
+    ($name:ident, $typename:ident) => {
+        #[doc = concat!("Creates a `", stringify!($name), "` of transaction `tx` by `client`.")]
+        #[must_use]
+        pub fn $name(client: u16, tx: u32) -> Self {
+            Transaction {
+                typename: TransactionType::$typename,
+                client,
+                tx,
+                amount: None,
+            }
+        }
+    };
+}
+impl Transaction {
+    amount_transaction_fn!(deposit, Deposit);
+    amount_transaction_fn!(withdrawal, Withdrawal);
+    reference_transaction_fn!(dispute, Dispute);
+    reference_transaction_fn!(resolve, Resolve);
+    reference_transaction_fn!(chargeback, Chargeback);
+
+    /// Type of the transaction.
+    #[must_use]
+    pub fn typename(&self) -> TransactionType {
+        self.typename
+    }
+    /// The client that performed the transaction.
+    #[must_use]
+    pub fn client(&self) -> u16 {
+        self.client
+    }
+    /// Transaction ID, for disputes, resolves and chargebacks the disputed transaction.
+    #[must_use]
+    pub fn tx(&self) -> u32 {
+        self.tx
+    }
+    /// Amount of deposits and withdrawals.
+    #[must_use]
+    pub fn amount(&self) -> Option<Amount> {
+        self.amount
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 /// Transaction types
-pub(crate) enum TransactionType {
+pub enum TransactionType {
+    /// Credit to the client account.
     Deposit,
+    /// Debit from the client account.
     Withdrawal,
+    /// Claim that an earlier transaction was erroneous.
     Dispute,
+    /// Ends a dispute, releasing the held funds.
     Resolve,
+    /// Ends a dispute by reversing the transaction, locks the account.
     Chargeback,
 }
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+/// Dispute lifecycle state of a stored deposit or withdrawal.
+///
+/// | From        | Event        | To          |
+/// |-------------|--------------|-------------|
+/// | Processed   | `dispute`    | Disputed    |
+/// | Disputed    | `resolve`    | Resolved    |
+/// | Disputed    | `chargeback` | ChargedBack |
+///
+/// Every other combination is refused, `Resolved` and `ChargedBack` are final.
+pub enum TxState {
+    /// Applied, not disputed.
+    Processed,
+    /// Under dispute, the amount is held.
+    Disputed,
+    /// The dispute was resolved and the amount released.
+    Resolved,
+    /// The dispute ended in a chargeback.
+    ChargedBack,
+}
+impl TxState {
+    /// Returns the state after `event`, or `None` if the transition is not allowed.
+    pub(crate) fn transition(self, event: &TransactionType) -> Option<TxState> {
+        match (self, event) {
+            (TxState::Processed, TransactionType::Dispute) => {
+                Some(TxState::Disputed)
+            }
+            (TxState::Disputed, TransactionType::Resolve) => {
+                Some(TxState::Resolved)
+            }
+            (TxState::Disputed, TransactionType::Chargeback) => {
+                Some(TxState::ChargedBack)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+/// Point in time copy of a stored deposit or withdrawal.
+pub struct TransactionSnapshot {
+    /// Transaction ID.
+    pub tx: u32,
+    /// Whether this is a deposit or a withdrawal.
+    pub typename: TransactionType,
+    /// The client that owns the transaction.
+    pub client: u16,
+    /// Amount of the transaction.
+    pub amount: Amount,
+    /// Where the transaction is in the dispute lifecycle.
+    pub state: TxState,
+}
+
+#[derive(Debug)]
+/// The outcome of processing a single transaction.
+pub enum TxOutcome {
+    /// The transaction was applied to the account.
+    Applied,
+    /// The transaction was refused and did not change any state.
+    Rejected(TransactionError),
+}
+impl TxOutcome {
+    /// Machine-readable outcome, `applied` or the rejection reason code.
+    pub fn code(&self) -> &'static str {
+        match self {
+            TxOutcome::Applied => "applied",
+            TxOutcome::Rejected(reason) => reason.code(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Transaction, TransactionType};
+
+    #[test]
+    fn test_constructors() {
+        let amount = "1.5".parse().unwrap();
+        let deposit = Transaction::deposit(1, 2, amount);
+        assert_eq!(deposit.typename(), TransactionType::Deposit);
+        assert_eq!(deposit.client(), 1);
+        assert_eq!(deposit.tx(), 2);
+        assert_eq!(deposit.amount(), Some(amount));
+        let dispute = Transaction::dispute(1, 2);
+        assert_eq!(dispute.typename(), TransactionType::Dispute);
+        assert_eq!(dispute.amount(), None);
+    }
+    #[test]
+    fn test_csv_round_trip() {
+        let transactions = vec![
+            Transaction::deposit(1, 1, "1.5".parse().unwrap()),
+            Transaction::withdrawal(1, 2, "0.0001".parse().unwrap()),
+            Transaction::dispute(1, 1),
+            Transaction::resolve(1, 1),
+            Transaction::chargeback(1, 1),
+        ];
+        let mut wtr = csv::Writer::from_writer(vec![]);
+        for transaction in &transactions {
+            assert!(wtr.serialize(transaction).is_ok());
+        }
+        let output = String::from_utf8(wtr.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            output,
+            "type,client,tx,amount\ndeposit,1,1,1.5\nwithdrawal,1,2,0.0001\n\
+             dispute,1,1,\nresolve,1,1,\nchargeback,1,1,\n"
+        );
+        let parsed: Vec<Transaction> =
+            csv::Reader::from_reader(output.as_bytes())
+                .deserialize()
+                .map(Result::unwrap)
+                .collect();
+        assert_eq!(parsed, transactions);
+    }
+}
@@ -1,38 +1,239 @@
+use crate::entities::transaction::TxState;
+#[cfg(feature = "runtime")]
 use crate::entities::EngineEvent;
 use csv::Error as csv_error;
 use std::io::Error as io_error;
 use thiserror::Error;
+#[cfg(feature = "runtime")]
 use tokio::sync::mpsc::error::SendError;
+#[cfg(feature = "runtime")]
 use tokio::task::JoinError;
 #[derive(Error, Debug)]
 /// File related errors.
 pub enum FileError {
+    /// Reading or writing csv failed.
     #[error("Unable read csv file: `{0}`")]
     CsvRead(#[from] csv_error),
+    /// Writing the output failed.
     #[error("Unable write csv to stdout: `{0}`")]
     StdOut(#[from] io_error),
+    /// The input path does not exist or is not a file.
+    #[error("File not found: `{0}`")]
+    NotFound(String),
+    /// The input path does not end in the extension of the input format.
+    #[error("Expected a .csv or .jsonl file matching the input format: `{0}`")]
+    WrongExtension(String),
+    /// Reading the input failed, the rest of it can not be read.
+    #[error("Unable to read input: `{0}`")]
+    Read(#[source] io_error),
+    /// Flushing the output failed.
+    #[error("Unable to flush csv output: `{0}`")]
+    Flush(#[source] io_error),
+    /// Opening the journal failed.
+    #[error("Unable to open journal: `{0}`")]
+    Journal(#[source] io_error),
+    /// A journal row does not hold the digest of the row before it.
+    #[error("Journal hash chain broken at line {line}")]
+    BrokenChain {
+        /// Line of the first row with a wrong `prev`.
+        line: u64,
+    },
+    /// Reading or writing a snapshot failed.
+    #[error("Unable to access snapshot: `{0}`")]
+    Snapshot(#[source] io_error),
+    /// A snapshot is not valid json.
+    #[error("Invalid snapshot: `{0}`")]
+    SnapshotFormat(#[from] serde_json::Error),
+    /// A snapshot was written by a newer version.
+    #[error("Snapshot version {found} is newer than the supported version {supported}")]
+    SnapshotVersion {
+        /// Version of the snapshot.
+        found: u32,
+        /// Latest version this build can read.
+        supported: u32,
+    },
+    /// Reading or writing the write-ahead log failed.
+    #[error("Unable to access write-ahead log: `{0}`")]
+    Wal(#[source] io_error),
+    /// A write-ahead log record is damaged before the end of the log.
+    #[error("Corrupt write-ahead log segment `{segment}` at byte {offset}")]
+    WalCorrupt {
+        /// Path of the damaged segment.
+        segment: String,
+        /// Offset of the first invalid record.
+        offset: u64,
+    },
+    /// Restoring a snapshot into the stores failed.
+    #[error(transparent)]
+    Store(#[from] StoreError),
+}
+#[derive(Error, Debug)]
+/// Amount parsing errors.
+pub enum AmountError {
+    /// Not a decimal number.
+    #[error("Invalid amount: `{0}`")]
+    Invalid(String),
+    /// More than four significant decimal places.
+    #[error("Amount has more than four decimal places: `{0}`")]
+    Precision(String),
+    /// Does not fit in the amount type.
+    #[error("Amount out of range: `{0}`")]
+    Overflow(String),
+}
+#[derive(Error, Debug)]
+/// Reasons for refusing a transaction.
+pub enum TransactionError {
+    /// The client disputing a transaction is not its owner.
+    #[error("Client {client} does not own transaction {tx} (owner {owner})")]
+    ClientMismatch {
+        /// The disputed transaction.
+        tx: u32,
+        /// The client that created the transaction.
+        owner: u16,
+        /// The client that referenced it.
+        client: u16,
+    },
+    /// A deposit or withdrawal reuses a transaction id.
+    #[error("Transaction {0} already exists")]
+    DuplicateTx(u32),
+    /// The referenced transaction does not exist.
+    #[error("Transaction {0} does not exist")]
+    UnknownTx(u32),
+    /// A deposit or withdrawal without an amount.
+    #[error("Transaction {0} has no amount")]
+    MissingAmount(u32),
+    /// A deposit or withdrawal of zero or a negative amount.
+    #[error("Transaction {0} amount is not positive")]
+    NonPositiveAmount(u32),
+    /// The dispute policy does not allow disputing this transaction.
+    #[error("Transaction {0} can not be disputed under the dispute policy")]
+    NotDisputable(u32),
+    /// The dispute lifecycle does not allow this step.
+    #[error("Transaction {tx} can not transition from state {state:?}")]
+    InvalidTransition {
+        /// The referenced transaction.
+        tx: u32,
+        /// Its current state.
+        state: TxState,
+    },
+    /// The account refused the change.
+    #[error(transparent)]
+    Account(#[from] AccountError),
+    /// The changes could not be stored, nothing was applied.
+    #[error(transparent)]
+    Store(#[from] StoreError),
+}
+impl TransactionError {
+    /// Machine-readable reason code, e.g. for the rejection output.
+    pub fn code(&self) -> &'static str {
+        match self {
+            TransactionError::ClientMismatch { .. } => "client_mismatch",
+            TransactionError::DuplicateTx(_) => "duplicate_tx",
+            TransactionError::UnknownTx(_) => "unknown_tx",
+            TransactionError::MissingAmount(_) => "missing_amount",
+            TransactionError::NonPositiveAmount(_) => "non_positive_amount",
+            TransactionError::NotDisputable(_) => "not_disputable",
+            TransactionError::InvalidTransition { .. } => "invalid_transition",
+            TransactionError::Account(e) => e.code(),
+            TransactionError::Store(_) => "store_error",
+        }
+    }
 }
 #[derive(Error, Debug)]
 /// Account related errors.
-pub(crate) enum AccountError {}
+pub enum AccountError {
+    /// Not enough available funds.
+    #[error("Insufficient available funds")]
+    InsufficientFunds,
+    /// Not enough held funds.
+    #[error("Insufficient held funds")]
+    InsufficientHeld,
+    /// The account is locked after a chargeback.
+    #[error("Account is locked")]
+    AccountLocked,
+    /// A balance would not fit in the amount type.
+    #[error("Balance would overflow")]
+    Overflow,
+}
+impl AccountError {
+    /// Machine-readable reason code, e.g. for the rejection output.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AccountError::InsufficientFunds => "insufficient_funds",
+            AccountError::InsufficientHeld => "insufficient_held",
+            AccountError::AccountLocked => "account_locked",
+            AccountError::Overflow => "overflow",
+        }
+    }
+}
+#[derive(Error, Debug)]
+/// Account or transaction store errors.
+pub enum StoreError {
+    /// The SQLite database failed.
+    #[cfg(feature = "sqlite")]
+    #[error("SQLite store failed: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    /// A stored record could not be read back.
+    #[error("Corrupt record in store: {0}")]
+    Corrupt(String),
+    /// Any other backend failure.
+    #[error("Store failed: {0}")]
+    Backend(String),
+}
 #[derive(Error, Debug)]
 /// Engine related errors.
 pub enum EngineError {
+    /// Input or output file error.
     #[error(transparent)]
     File(#[from] FileError),
+    /// The account or transaction store failed.
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    /// A row could not be parsed.
     #[error("Invalid row in csv file: ${0}")]
     ParseRow(#[from] csv_error),
+    /// A json line could not be parsed.
+    #[error("Invalid json on line {line}: {source}")]
+    ParseJson {
+        /// Line of the input.
+        line: u64,
+        /// Why it could not be parsed.
+        #[source]
+        source: serde_json::Error,
+    },
+    /// The engine channel is closed.
+    #[cfg(feature = "runtime")]
     #[error("Failed to send transaction onto channel: ${0}")]
     ChannelSend(#[from] SendError<EngineEvent>),
+    /// A task failed to complete.
+    #[cfg(feature = "runtime")]
     #[error("Failed to terminate engine runner: ${0}")]
     Terminate(#[from] JoinError),
+    /// Unknown event.
     #[error("Unknown event ${0}")]
     Event(String),
+    /// The rejection writer stopped.
+    #[error("Failed to send row to rejection writer")]
+    RejectionSend,
+    /// The payment engine stopped before finishing.
+    #[error("Payment engine stopped unexpectedly: {0}")]
+    EngineCrashed(String),
+    /// Validation found this many issues in the input.
+    #[error("Input failed validation with {0} issues")]
+    Invalid(usize),
 }
-#[cfg(test)]
-#[derive(Error, Debug)]
-/// Test related errors.
-pub(crate) enum TestError {
-    #[error("Unexpected io error: `{0}`")]
-    StdOut(#[from] io_error),
+impl EngineError {
+    /// Whether this is a row that can not be parsed, which lenient parsing
+    /// skips. Failing reads and invalid UTF-8 are not, the rest of the input
+    /// can not be trusted.
+    #[cfg(feature = "runtime")]
+    pub(crate) fn is_malformed_row(&self) -> bool {
+        match self {
+            EngineError::ParseRow(e) => {
+                matches!(e.kind(), csv::ErrorKind::Deserialize { .. })
+            }
+            EngineError::ParseJson { .. } => true,
+            _ => false,
+        }
+    }
 }
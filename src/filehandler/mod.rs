@@ -1,25 +1,59 @@
 //! Filehandler logic, for reading csv files and writing to stdout.
 
-use crate::entities::account::Account;
+#[cfg(feature = "runtime")]
+use crate::entities::channel::Rx;
+#[cfg(feature = "runtime")]
+use crate::entities::rejection::{Rejection, Row, MALFORMED};
+use crate::entities::transaction::Transaction;
+#[cfg(feature = "runtime")]
+use crate::entities::transaction::TxOutcome;
 
-use crate::errors::FileError;
-use csv::{Reader, ReaderBuilder, Trim::All, Writer};
+use crate::errors::{EngineError, FileError};
+use csv::{Position, ReaderBuilder, Trim::All, Writer};
+use serde::Serialize;
+use std::cell::RefCell;
 use std::ffi::OsStr;
 use std::fs::File;
-use std::io::Write;
+use std::io::{self, BufRead, BufReader, Read, Write};
 use std::path::Path;
-/// Reads a csv file.
-/// Expects a valid path csv as input
-/// Returns a reader with the content of csv file.
-/// Will panic if file does not exists or wrong extension.
-pub(crate) fn read_csv(file_path: &str) -> Result<Reader<File>, FileError> {
-    let path = Path::new(file_path);
-    assert!(path.exists());
-    assert!(path.is_file());
-    assert_eq!(path.extension(), Some(OsStr::new("csv")));
+use std::rc::Rc;
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+/// Format of the transaction input.
+pub enum InputFormat {
+    /// Csv with a `type, client, tx, amount` header, `.csv` files.
+    #[default]
+    Csv,
+    /// One json object per line with the same fields, amounts as strings,
+    /// e.g. `{"type": "deposit", "client": 1, "tx": 1, "amount": "1.5"}`,
+    /// `.jsonl` files.
+    JsonLines,
+}
+impl InputFormat {
+    /// File extension of input files in this format.
+    fn extension(self) -> &'static str {
+        match self {
+            InputFormat::Csv => "csv",
+            InputFormat::JsonLines => "jsonl",
+        }
+    }
+}
+
+/// An input row, parsed into a transaction if possible.
+pub(crate) struct ParsedRow {
+    /// Line number of the row in the input, a csv header is line 1.
+    pub(crate) line: u64,
+    /// The row as read, without its line ending, empty if unreadable.
+    pub(crate) raw: String,
+    /// The transaction, or why the row is malformed.
+    pub(crate) tx: Result<Transaction, EngineError>,
+}
+
+/// Csv reader settings shared by all input sources.
+fn reader_builder() -> ReaderBuilder {
     //https://docs.rs/csv/latest/csv/struct.ReaderBuilder.html
     let mut binding = ReaderBuilder::new();
-    let rdr = binding
+    binding
         .delimiter(b',')
         .has_headers(true)
         .trim(All)
@@ -27,20 +61,282 @@ pub(crate) fn read_csv(file_path: &str) -> Result<Reader<File>, FileError> {
         .flexible(true) // This is somewhat difficult to understand from the requirement, since some inputs does not have `amount` do I still force `,` ?
         .double_quote(false)
         .quoting(false);
-    Ok(rdr.from_path(path)?)
+    binding
+}
+/// Opens an input file.
+/// Fails if file does not exists or has the wrong extension for `format`.
+pub(crate) fn open_input(
+    file_path: &str,
+    format: InputFormat,
+) -> Result<File, FileError> {
+    let path = Path::new(file_path);
+    if !path.is_file() {
+        return Err(FileError::NotFound(file_path.to_string()));
+    }
+    if path.extension() != Some(OsStr::new(format.extension())) {
+        return Err(FileError::WrongExtension(file_path.to_string()));
+    }
+    File::open(path).map_err(FileError::Read)
+}
+/// Reads a csv file.
+/// Expects a valid path csv as input
+/// Returns a reader with the content of csv file.
+/// Fails if file does not exists or has the wrong extension.
+#[cfg(all(test, feature = "runtime"))]
+pub(crate) fn read_csv(
+    file_path: &str,
+) -> Result<csv::Reader<File>, FileError> {
+    let file = open_input(file_path, InputFormat::Csv)?;
+    Ok(reader_builder().from_reader(file))
+}
+/// Reads the transactions in `reader`, row by row.
+/// Fails if the csv header can not be read.
+pub(crate) fn transaction_rows<'r, R: Read + 'r>(
+    reader: R,
+    format: InputFormat,
+) -> Result<Box<dyn Iterator<Item = ParsedRow> + 'r>, EngineError> {
+    Ok(match format {
+        InputFormat::Csv => Box::new(csv_rows(reader)?),
+        InputFormat::JsonLines => Box::new(json_rows(reader)),
+    })
+}
+/// Input bytes read ahead of the csv reader, from `offset` on, kept until
+/// the rows they hold are taken.
+#[derive(Default)]
+struct Tape {
+    bytes: Vec<u8>,
+    /// Input position of the first byte.
+    offset: u64,
+}
+impl Tape {
+    /// The line starting at input position `start`, without its line ending.
+    /// Rows are never quoted, so a row ends at the first newline.
+    fn line_at(&mut self, start: u64) -> String {
+        let skip = start.saturating_sub(self.offset) as usize;
+        let skip = skip.min(self.bytes.len());
+        // A row's position may be at the `\n` of a `\r\n` ending, or at the
+        // blank or comment lines the reader skipped before it.
+        let line = self.bytes[skip..]
+            .split(|&b| b == b'\n')
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+            .find(|line| !line.is_empty() && !line.starts_with(b"#"))
+            .unwrap_or_default();
+        let line = String::from_utf8_lossy(line).into_owned();
+        // Rows before `start` are done, drop them once they fill half the
+        // tape, so each byte is moved a bounded number of times.
+        if skip > self.bytes.len() / 2 {
+            self.bytes.drain(..skip);
+            self.offset += skip as u64;
+        }
+        line
+    }
+}
+
+/// Reader that copies everything read to a [`Tape`].
+struct Recorded<R> {
+    inner: R,
+    tape: Rc<RefCell<Tape>>,
+}
+impl<R: Read> Read for Recorded<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.tape.borrow_mut().bytes.extend_from_slice(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// Csv rows of `reader`, deserialized with its header.
+fn csv_rows<R: Read>(
+    reader: R,
+) -> Result<impl Iterator<Item = ParsedRow>, EngineError> {
+    let tape = Rc::new(RefCell::new(Tape::default()));
+    let mut content = reader_builder().from_reader(Recorded {
+        inner: reader,
+        tape: Rc::clone(&tape),
+    });
+    let headers = content.headers().map_err(read_error)?.clone();
+    let mut failed = false;
+    // The reader returns a failing read again and again, stop after it.
+    Ok(content.into_records().map_while(move |record| {
+        if failed {
+            return None;
+        }
+        failed = record.as_ref().is_err_and(csv::Error::is_io_error);
+        let (record, parsed) = match record {
+            Ok(record) => {
+                let tx = record.deserialize::<Transaction>(Some(&headers));
+                (Some(record), tx)
+            }
+            Err(e) => (None, Err(e)),
+        };
+        let position = match (&record, &parsed) {
+            (Some(record), _) => record.position(),
+            (None, Err(e)) => e.position(),
+            (None, Ok(_)) => None,
+        };
+        Some(ParsedRow {
+            line: position.map_or(0, Position::line),
+            raw: position
+                .map(|p| tape.borrow_mut().line_at(p.byte()))
+                .unwrap_or_default(),
+            tx: parsed.map_err(read_error),
+        })
+    }))
+}
+/// A csv error as an engine error, failing reads and invalid UTF-8 as
+/// [`FileError::Read`], like json lines.
+fn read_error(e: csv::Error) -> EngineError {
+    let kind = match e.kind() {
+        csv::ErrorKind::Io(_) => io::ErrorKind::Other,
+        csv::ErrorKind::Utf8 { .. } => io::ErrorKind::InvalidData,
+        _ => return e.into(),
+    };
+    FileError::Read(io::Error::new(kind, e)).into()
+}
+/// Json lines of `reader`, blank lines are skipped.
+/// Ends after the first line that can not be read.
+fn json_rows<R: Read>(reader: R) -> impl Iterator<Item = ParsedRow> {
+    let mut lines = BufReader::new(reader).lines();
+    let mut line = 0;
+    let mut failed = false;
+    std::iter::from_fn(move || loop {
+        if failed {
+            return None;
+        }
+        line += 1;
+        let text = match lines.next()? {
+            Ok(text) => text,
+            Err(e) => {
+                failed = true;
+                return Some(ParsedRow {
+                    line,
+                    raw: String::new(),
+                    tx: Err(FileError::Read(e).into()),
+                });
+            }
+        };
+        if text.trim().is_empty() {
+            continue;
+        }
+        let tx = serde_json::from_str(&text)
+            .map_err(|source| EngineError::ParseJson { line, source });
+        return Some(ParsedRow {
+            line,
+            raw: text,
+            tx,
+        });
+    })
 }
 /// Write account information to stdout
+/// The journal chain `head`, if any, follows as a `#` comment line, which
+/// csv input skips.
 /// Considerations:
 /// + Maybe use AsyncWrite instead?
 /// + Should this be in filehandler?
-pub(crate) fn csv_to_stdout<S: Write>(
-    accounts: Vec<&Account>,
+pub(crate) fn csv_to_stdout<A: Serialize, S: Write>(
+    accounts: impl IntoIterator<Item = A>,
+    head: Option<&str>,
     stream: S,
 ) -> Result<(), FileError> {
     let mut wtr = Writer::from_writer(stream);
     for account in accounts {
         wtr.serialize(account)?;
     }
-    assert!(wtr.flush().is_ok(), "Unable to flush stream");
+    let mut stream = wtr
+        .into_inner()
+        .map_err(|e| FileError::Flush(e.into_error()))?;
+    if let Some(head) = head {
+        writeln!(stream, "# journal head: {head}").map_err(FileError::Flush)?;
+        stream.flush().map_err(FileError::Flush)?;
+    }
+    Ok(())
+}
+
+/// Creates a csv writer to a file, truncating it if it exists.
+#[cfg(feature = "runtime")]
+pub(crate) fn csv_file_writer(
+    file_path: &str,
+) -> Result<Writer<File>, FileError> {
+    Ok(Writer::from_path(file_path)?)
+}
+/// Writes every rejected transaction and malformed row to `wtr`.
+/// Pairs each outcome with the input row it belongs to, `outcomes` must
+/// receive exactly one message per row that is not malformed, in the same order.
+#[cfg(feature = "runtime")]
+pub(crate) async fn write_rejections<S: Write>(
+    mut outcomes: Rx<TxOutcome>,
+    mut rows: Rx<Row>,
+    mut wtr: Writer<S>,
+) -> Result<(), FileError> {
+    while let Some(row) = rows.receive.recv().await {
+        let reason = if row.malformed {
+            Some(MALFORMED)
+        } else {
+            match outcomes.receive.recv().await {
+                Some(TxOutcome::Rejected(reason)) => Some(reason.code()),
+                Some(TxOutcome::Applied) => None,
+                // The engine stopped, its error is reported by the caller.
+                None => break,
+            }
+        };
+        if let Some(reason) = reason {
+            wtr.serialize(Rejection {
+                line: row.line,
+                reason,
+                row: &row.raw,
+            })?;
+        }
+    }
+    wtr.flush().map_err(FileError::Flush)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{transaction_rows, InputFormat};
+    use crate::errors::{EngineError, FileError};
+    use crate::tests::Failing;
+    use std::io::Read;
+
+    #[test]
+    fn test_failing_reader() {
+        let read_error = |e: &EngineError| {
+            matches!(e, EngineError::File(FileError::Read(_)))
+        };
+        let rows: Vec<_> = transaction_rows(Failing, InputFormat::JsonLines)
+            .unwrap()
+            .collect();
+        assert_eq!(rows.len(), 1);
+        assert!(rows[0].tx.as_ref().is_err_and(read_error));
+        let header = transaction_rows(Failing, InputFormat::Csv);
+        assert!(header.is_err_and(|e| read_error(&e)));
+        let input =
+            b"type, client, tx, amount\ndeposit, 1, 1, 1.0\n".chain(Failing);
+        let rows: Vec<_> =
+            transaction_rows(input, InputFormat::Csv).unwrap().collect();
+        assert_eq!(rows.len(), 2);
+        assert!(rows[0].tx.is_ok());
+        assert!(rows[1].tx.as_ref().is_err_and(read_error));
+    }
+
+    #[test]
+    fn test_raw_rows() {
+        let mut input = b"type, client, tx, amount\n".to_vec();
+        for tx in 1..=2000 {
+            input.extend(format!("deposit, 1, {tx}, 1.0\n").bytes());
+        }
+        input.extend(b"withdrawal, 1, 2001, 1.0\r\n");
+        input.extend(b"# comment\ndeposit,\t2, 1, \xff\n");
+        let rows: Vec<_> = transaction_rows(input.as_slice(), InputFormat::Csv)
+            .unwrap()
+            .collect();
+        assert_eq!(rows.len(), 2002);
+        assert_eq!(rows[1999].line, 2001);
+        assert_eq!(rows[1999].raw, "deposit, 1, 2000, 1.0");
+        assert_eq!(rows[2000].raw, "withdrawal, 1, 2001, 1.0");
+        // Unreadable rows keep their text too.
+        let last = &rows[2001];
+        assert!(last.tx.is_err());
+        assert_eq!(last.raw, "deposit,\t2, 1, \u{fffd}");
+    }
+}
@@ -0,0 +1,272 @@
+//! Synthetic transaction workloads, for benchmarks and soak tests.
+//!
+//! The stream is generated row by row, so it can be far larger than memory,
+//! and depends only on the [`Workload`], the same seed always gives the same
+//! rows. Only recent deposits and open disputes are remembered.
+use crate::entities::amount::Amount;
+use crate::errors::FileError;
+use crate::filehandler::InputFormat;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::num::NonZeroU16;
+
+/// Number of recent deposits disputes are drawn from.
+const RECENT_DEPOSITS: usize = 4096;
+/// Largest generated amount, in ten-thousandths.
+const MAX_UNITS: u64 = 10_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// Shape of a generated workload.
+pub struct Workload {
+    /// Number of clients, transactions are spread evenly over them.
+    pub clients: NonZeroU16,
+    /// Number of rows to generate.
+    pub transactions: u32,
+    /// Share of rows that dispute a recent deposit, between 0 and 1.
+    /// Disputes are settled later at about the same rate.
+    pub dispute_rate: f64,
+    /// Share of disputes settled with a chargeback instead of a resolve,
+    /// between 0 and 1.
+    pub chargeback_rate: f64,
+    /// Share of rows that can not be parsed, between 0 and 1.
+    pub malformed_ratio: f64,
+    /// Seed of the random stream.
+    pub seed: u64,
+    /// Format of the rows.
+    pub format: InputFormat,
+}
+
+impl Default for Workload {
+    fn default() -> Self {
+        Workload {
+            clients: NonZeroU16::MIN.saturating_add(999),
+            transactions: 100_000,
+            dispute_rate: 0.01,
+            chargeback_rate: 0.1,
+            malformed_ratio: 0.0,
+            seed: 0,
+            format: InputFormat::Csv,
+        }
+    }
+}
+
+/// SplitMix64, small and stable across releases, unlike library generators
+/// whose streams may change between versions.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `0..n`, `n` must not be 0.
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    /// True with probability `p`.
+    fn chance(&mut self, p: f64) -> bool {
+        // 53 random bits, uniform in [0, 1).
+        ((self.next() >> 11) as f64 / (1_u64 << 53) as f64) < p
+    }
+}
+
+/// A generated row, before formatting.
+enum Event {
+    Deposit(u16, u32, Amount),
+    Withdrawal(u16, u32, Amount),
+    Dispute(u16, u32),
+    Resolve(u16, u32),
+    Chargeback(u16, u32),
+    Malformed(u16, u32, u64),
+}
+
+/// Writes the rows of `workload` to `out`, with a header for csv.
+pub fn generate<W: Write>(
+    workload: &Workload,
+    mut out: W,
+) -> Result<(), FileError> {
+    let mut rng = SplitMix64(workload.seed);
+    let clients = u64::from(workload.clients.get());
+    let mut recent: VecDeque<(u16, u32)> = VecDeque::new();
+    let mut open: Vec<(u16, u32)> = Vec::new();
+    let mut next_tx: u32 = 1;
+    if workload.format == InputFormat::Csv {
+        writeln!(out, "type,client,tx,amount")?;
+    }
+    for _ in 0..workload.transactions {
+        let client = rng.below(clients) as u16 + 1;
+        let event = if rng.chance(workload.malformed_ratio) {
+            let tx = next_tx;
+            next_tx = next_tx.wrapping_add(1);
+            Event::Malformed(client, tx, rng.below(4))
+        } else if !open.is_empty() && rng.chance(workload.dispute_rate) {
+            let (client, tx) =
+                open.swap_remove(rng.below(open.len() as u64) as usize);
+            if rng.chance(workload.chargeback_rate) {
+                Event::Chargeback(client, tx)
+            } else {
+                Event::Resolve(client, tx)
+            }
+        } else if !recent.is_empty() && rng.chance(workload.dispute_rate) {
+            let pick = rng.below(recent.len() as u64) as usize;
+            // Each deposit is disputed at most once.
+            let (client, tx) =
+                recent.swap_remove_back(pick).unwrap_or_default();
+            open.push((client, tx));
+            Event::Dispute(client, tx)
+        } else {
+            let amount = Amount::from_units(rng.below(MAX_UNITS) as i64 + 1);
+            let tx = next_tx;
+            next_tx = next_tx.wrapping_add(1);
+            // Deposits outnumber withdrawals, so most withdrawals succeed.
+            if rng.chance(0.6) {
+                if recent.len() == RECENT_DEPOSITS {
+                    recent.pop_front();
+                }
+                recent.push_back((client, tx));
+                Event::Deposit(client, tx, amount)
+            } else {
+                Event::Withdrawal(client, tx, amount)
+            }
+        };
+        write_event(&mut out, workload.format, &event)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Writes `event` as a single row in `format`.
+fn write_event<W: Write>(
+    out: &mut W,
+    format: InputFormat,
+    event: &Event,
+) -> Result<(), FileError> {
+    let (kind, client, tx, amount) = match *event {
+        Event::Deposit(client, tx, amount) => {
+            ("deposit", client, tx, Some(amount))
+        }
+        Event::Withdrawal(client, tx, amount) => {
+            ("withdrawal", client, tx, Some(amount))
+        }
+        Event::Dispute(client, tx) => ("dispute", client, tx, None),
+        Event::Resolve(client, tx) => ("resolve", client, tx, None),
+        Event::Chargeback(client, tx) => ("chargeback", client, tx, None),
+        Event::Malformed(client, tx, variant) => {
+            return write_malformed(out, format, client, tx, variant);
+        }
+    };
+    match (format, amount) {
+        (InputFormat::Csv, Some(amount)) => {
+            writeln!(out, "{kind},{client},{tx},{amount}")?;
+        }
+        (InputFormat::Csv, None) => writeln!(out, "{kind},{client},{tx},")?,
+        (InputFormat::JsonLines, Some(amount)) => writeln!(
+            out,
+            r#"{{"type":"{kind}","client":{client},"tx":{tx},"amount":"{amount}"}}"#
+        )?,
+        (InputFormat::JsonLines, None) => {
+            writeln!(out, r#"{{"type":"{kind}","client":{client},"tx":{tx}}}"#)?
+        }
+    }
+    Ok(())
+}
+
+/// Writes one of a few kinds of unparseable rows, picked by `variant`.
+fn write_malformed<W: Write>(
+    out: &mut W,
+    format: InputFormat,
+    client: u16,
+    tx: u32,
+    variant: u64,
+) -> Result<(), FileError> {
+    match (format, variant) {
+        (InputFormat::Csv, 0) => writeln!(out, "transfer,{client},{tx},1.0")?,
+        (InputFormat::Csv, 1) => writeln!(out, "deposit,x{client},{tx},1.0")?,
+        (InputFormat::Csv, 2) => {
+            writeln!(out, "deposit,{client},{tx},1.23456")?
+        }
+        (InputFormat::Csv, _) => writeln!(out, "deposit,{client},{tx},abc")?,
+        (InputFormat::JsonLines, 0) => writeln!(
+            out,
+            r#"{{"type":"transfer","client":{client},"tx":{tx},"amount":"1.0"}}"#
+        )?,
+        (InputFormat::JsonLines, 1) => writeln!(
+            out,
+            r#"{{"type":"deposit","client":"x{client}","tx":{tx},"amount":"1.0"}}"#
+        )?,
+        (InputFormat::JsonLines, 2) => writeln!(
+            out,
+            r#"{{"type":"deposit","client":{client},"tx":{tx},"amount":"1.23456"}}"#
+        )?,
+        (InputFormat::JsonLines, _) => {
+            writeln!(out, r#"{{"type":"deposit","client":{client},"tx":{tx}"#)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generate, Workload};
+    use crate::filehandler::InputFormat;
+    use crate::validate::{validate, IssueKind};
+
+    fn rows(workload: &Workload) -> String {
+        let mut out = vec![];
+        assert!(generate(workload, &mut out).is_ok());
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_reproducible() {
+        let workload = Workload {
+            transactions: 1000,
+            dispute_rate: 0.2,
+            malformed_ratio: 0.1,
+            ..Default::default()
+        };
+        assert_eq!(rows(&workload), rows(&workload));
+        let other = Workload {
+            seed: 1,
+            ..workload
+        };
+        assert_ne!(rows(&workload), rows(&other));
+    }
+
+    #[test]
+    fn test_generated_rows_validate() {
+        for format in [InputFormat::Csv, InputFormat::JsonLines] {
+            let workload = Workload {
+                transactions: 10_000,
+                dispute_rate: 0.1,
+                chargeback_rate: 0.5,
+                format,
+                ..Default::default()
+            };
+            let content = rows(&workload);
+            for kind in ["dispute", "resolve", "chargeback"] {
+                assert!(content.contains(kind));
+            }
+            let validation = validate(content.as_bytes(), format).unwrap();
+            assert_eq!(validation.rows, 10_000);
+            assert!(validation.is_valid(), "{validation}");
+            // Malformed rows are the only issues.
+            let workload = Workload {
+                malformed_ratio: 0.05,
+                ..workload
+            };
+            let validation =
+                validate(rows(&workload).as_bytes(), format).unwrap();
+            assert!(!validation.is_valid());
+            assert!(validation
+                .issues
+                .iter()
+                .all(|issue| issue.kind == IssueKind::Schema));
+        }
+    }
+}
@@ -0,0 +1,461 @@
+//! Append-only, hash-chained event journal.
+//!
+//! Every transaction the engine receives is appended before it is applied,
+//! followed by its outcome once applied, as csv:
+//!
+//! ``` csv
+//! type,client,tx,amount,outcome,prev
+//! deposit,1,1,1.5,,0000000000000000000000000000000000000000000000000000000000000000
+//! deposit,1,1,1.5,applied,9d6b...
+//! withdrawal,1,2,5,,41f0...
+//! withdrawal,1,2,5,insufficient_funds,c27e...
+//! ```
+//!
+//! Rows without an outcome are what the engine saw, in order, [`replay`]
+//! applies them again to rebuild the engine. A transaction without an outcome
+//! row was interrupted, replaying it finishes it.
+//!
+//! `prev` is the SHA-256 of the previous row as written, newline included,
+//! all zeros for the first, so editing, reformatting, inserting or removing a
+//! row breaks every link after it, see [`verify_journal`]. The digest of the last row is the head of the chain,
+//! removing rows at the end is only detected by comparing it with a head
+//! recorded earlier, e.g. from a report.
+//!
+//! Every row is synced to disk once written. A crash while appending leaves
+//! a torn final row without its newline, [`Journal::open`] truncates it.
+use crate::engine::Engine;
+use crate::entities::amount::Amount;
+use crate::entities::policy::EngineConfig;
+use crate::entities::transaction::{Transaction, TransactionType, TxOutcome};
+use crate::errors::FileError;
+use crate::store::{AccountStore, TransactionStore};
+use csv::{ReaderBuilder, StringRecord, WriterBuilder};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use tracing::warn;
+
+/// Csv header of the journal.
+const HEADERS: &[u8] = b"type,client,tx,amount,outcome,prev\n";
+
+/// `prev` of the first row.
+const GENESIS: [u8; 32] = [0; 32];
+
+#[derive(Serialize, Deserialize)]
+/// A journal row, the transaction and, once applied, its outcome.
+struct Record {
+    #[serde(rename = "type")]
+    typename: TransactionType,
+    client: u16,
+    tx: u32,
+    amount: Option<Amount>,
+    /// Empty before the transaction is applied, see [`TxOutcome::code`].
+    outcome: Option<String>,
+    /// Hex SHA-256 of the previous row.
+    prev: Option<String>,
+}
+
+impl Record {
+    /// A row for `transaction`, before it is applied.
+    fn new(transaction: &Transaction) -> Self {
+        Record {
+            typename: transaction.typename,
+            client: transaction.client,
+            tx: transaction.tx,
+            amount: transaction.amount,
+            outcome: None,
+            prev: None,
+        }
+    }
+
+    /// The row as written to the journal, without headers.
+    fn to_row(&self) -> Result<Vec<u8>, FileError> {
+        let mut wtr = WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(Vec::new());
+        wtr.serialize(self)?;
+        wtr.into_inner()
+            .map_err(|e| FileError::Journal(e.into_error()))
+    }
+}
+
+/// Hex encoding of a digest.
+fn hex(digest: &[u8; 32]) -> String {
+    digest.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Journal writer, see the module documentation for the format.
+pub struct Journal {
+    wtr: Box<dyn Write + Send>,
+    /// Whether the headers are still to be written.
+    headers: bool,
+    /// Digest of the last row.
+    head: [u8; 32],
+    /// The journal file, synced after every row, if opened from a path.
+    file: Option<File>,
+}
+
+impl Journal {
+    /// Opens the journal at `path` for appending, creating it if needed.
+    /// New rows are chained to the last row already in the journal.
+    /// A torn final row is truncated, a broken chain is refused with
+    /// [`FileError::BrokenChain`].
+    pub fn open(path: &str) -> Result<Self, FileError> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(path)
+            .map_err(FileError::Journal)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes).map_err(FileError::Journal)?;
+        // Rows are written whole, newline last, anything after the last
+        // newline was torn by a crash.
+        let valid =
+            bytes.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+        if valid != bytes.len() {
+            warn!(
+                journal = path,
+                dropped = bytes.len() - valid,
+                "Truncating torn journal row"
+            );
+            file.set_len(valid as u64).map_err(FileError::Journal)?;
+            file.sync_all().map_err(FileError::Journal)?;
+        }
+        let head = chain(&bytes[..valid])?;
+        let sync = file.try_clone().map_err(FileError::Journal)?;
+        Ok(Journal {
+            wtr: Box::new(file),
+            headers: valid == 0,
+            head,
+            file: Some(sync),
+        })
+    }
+
+    /// Writes a new journal to `wtr`.
+    pub fn from_writer<W: Write + Send + 'static>(wtr: W) -> Self {
+        Journal {
+            wtr: Box::new(wtr),
+            headers: true,
+            head: GENESIS,
+            file: None,
+        }
+    }
+
+    /// Hex SHA-256 of the last row, the head of the chain.
+    #[must_use]
+    pub fn head(&self) -> String {
+        hex(&self.head)
+    }
+
+    /// Journals `transaction`, applies it to `engine`, and journals the outcome.
+    /// The transaction is not applied if it can not be journaled.
+    pub fn apply<A: AccountStore, T: TransactionStore>(
+        &mut self,
+        engine: &mut Engine<A, T>,
+        transaction: Transaction,
+    ) -> Result<TxOutcome, FileError> {
+        let record = Record::new(&transaction);
+        self.append(&record)?;
+        let outcome = engine.apply(transaction);
+        self.append(&Record {
+            outcome: Some(outcome.code().to_string()),
+            ..record
+        })?;
+        Ok(outcome)
+    }
+
+    fn append(&mut self, record: &Record) -> Result<(), FileError> {
+        let row = Record {
+            prev: Some(self.head()),
+            outcome: record.outcome.clone(),
+            ..*record
+        }
+        .to_row()?;
+        if self.headers {
+            self.wtr.write_all(HEADERS).map_err(FileError::Journal)?;
+            self.headers = false;
+        }
+        self.wtr.write_all(&row).map_err(FileError::Journal)?;
+        self.wtr.flush().map_err(FileError::Flush)?;
+        if let Some(file) = &self.file {
+            file.sync_data().map_err(FileError::Journal)?;
+        }
+        self.head = Sha256::digest(&row).into();
+        Ok(())
+    }
+}
+
+/// Walks the hash chain of `journal`, returning its head.
+/// Fails with [`FileError::BrokenChain`] at the first row whose `prev` is not
+/// the digest of the row before it.
+pub fn verify_journal<R: Read>(journal: R) -> Result<String, FileError> {
+    Ok(hex(&chain(journal)?))
+}
+
+/// Digest of the last row of `journal`, see [`verify_journal`].
+/// Rows are hashed as written, newline included, so reformatting a row
+/// breaks the chain as much as editing its values.
+fn chain<R: Read>(journal: R) -> Result<[u8; 32], FileError> {
+    let mut lines = BufReader::new(journal);
+    let mut row = Vec::new();
+    lines
+        .read_until(b'\n', &mut row)
+        .map_err(FileError::Journal)?;
+    let headers = ReaderBuilder::new()
+        .from_reader(row.as_slice())
+        .headers()?
+        .clone();
+    let mut head = GENESIS;
+    let mut fields = StringRecord::new();
+    for line in 2.. {
+        row.clear();
+        if lines
+            .read_until(b'\n', &mut row)
+            .map_err(FileError::Journal)?
+            == 0
+        {
+            break;
+        }
+        let mut rdr = ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(row.as_slice());
+        // A blank line is not a row, but still an edit of the journal.
+        let record: Option<Record> = match rdr.read_record(&mut fields)? {
+            true => Some(fields.deserialize(Some(&headers))?),
+            false => None,
+        };
+        if record.and_then(|r| r.prev).as_deref() != Some(hex(&head).as_str()) {
+            return Err(FileError::BrokenChain { line });
+        }
+        head = Sha256::digest(&row).into();
+    }
+    Ok(head)
+}
+
+/// Rebuilds an engine from the journal at `path`.
+pub fn replay(path: &str, config: EngineConfig) -> Result<Engine, FileError> {
+    let mut engine = Engine::new(config);
+    replay_into(&mut engine, File::open(path).map_err(FileError::Journal)?)?;
+    Ok(engine)
+}
+
+/// Applies every journaled transaction in `journal` to `engine`, returning
+/// how many were applied or rejected.
+/// Recorded outcomes that differ from the replayed ones are logged, e.g.
+/// when replaying with another [`EngineConfig`].
+pub fn replay_into<A: AccountStore, T: TransactionStore, R: Read>(
+    engine: &mut Engine<A, T>,
+    journal: R,
+) -> Result<u64, FileError> {
+    replay_from(engine, journal, 0)
+}
+
+/// Same as [`replay_into`], skipping the first `position` transactions,
+/// e.g. those already in a [`StateSnapshot`](crate::StateSnapshot).
+/// Returns the position after the journal, at least `position`.
+pub fn replay_from<A: AccountStore, T: TransactionStore, R: Read>(
+    engine: &mut Engine<A, T>,
+    journal: R,
+    position: u64,
+) -> Result<u64, FileError> {
+    let mut last: Option<TxOutcome> = None;
+    let mut count = 0;
+    let mut rdr = ReaderBuilder::new().flexible(true).from_reader(journal);
+    for record in rdr.deserialize() {
+        let record: Record = record?;
+        match record.outcome {
+            None => {
+                count += 1;
+                if count <= position {
+                    continue;
+                }
+                last = Some(engine.apply(Transaction {
+                    typename: record.typename,
+                    client: record.client,
+                    tx: record.tx,
+                    amount: record.amount,
+                }));
+            }
+            Some(recorded) => {
+                let Some(replayed) = last.take() else {
+                    continue; // Skipped, or journaled twice.
+                };
+                let replayed = replayed.code();
+                if recorded != replayed {
+                    warn!(
+                        tx = record.tx,
+                        recorded,
+                        replayed,
+                        "Replayed outcome differs from journal"
+                    );
+                }
+            }
+        }
+    }
+    Ok(count.max(position))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{replay_into, verify_journal, Journal};
+    use crate::engine::Engine;
+    use crate::entities::policy::EngineConfig;
+    use crate::entities::transaction::{Transaction, TxOutcome};
+    use crate::errors::FileError;
+    use crate::tests::{Shared, TempPath};
+    use std::fs::{self, OpenOptions};
+
+    #[test]
+    fn test_journal_replay() {
+        let buffer = Shared::default();
+        let mut journal = Journal::from_writer(buffer.clone());
+        let mut engine = Engine::new(EngineConfig::default());
+        let amount = "1.5".parse().unwrap();
+        for event in [
+            Transaction::deposit(1, 1, amount),
+            Transaction::withdrawal(1, 2, "5".parse().unwrap()),
+            Transaction::dispute(1, 1),
+        ] {
+            assert!(journal.apply(&mut engine, event).is_ok());
+        }
+        let written = buffer.text();
+        // Every row ends with the digest of the previous row.
+        let rows: Vec<_> = written
+            .lines()
+            .map(|row| row.rsplit_once(',').unwrap().0)
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                "type,client,tx,amount,outcome",
+                "deposit,1,1,1.5,",
+                "deposit,1,1,1.5,applied",
+                "withdrawal,1,2,5,",
+                "withdrawal,1,2,5,insufficient_funds",
+                "dispute,1,1,,",
+                "dispute,1,1,,applied",
+            ]
+        );
+        assert_eq!(verify_journal(written.as_bytes()).unwrap(), journal.head());
+        let mut replayed = Engine::new(EngineConfig::default());
+        let journal = buffer.contents();
+        assert_eq!(replay_into(&mut replayed, journal.as_slice()).unwrap(), 3);
+        assert_eq!(replayed.snapshot(), engine.snapshot());
+        assert_eq!(replayed.transaction(1), engine.transaction(1));
+    }
+
+    #[test]
+    fn test_replay_interrupted() {
+        // The last transaction was journaled, but the outcome was not.
+        let journal = "type,client,tx,amount,outcome\n\
+                       deposit,1,1,2,\n\
+                       deposit,1,1,2,applied\n\
+                       withdrawal,1,2,1,\n";
+        let mut engine = Engine::new(EngineConfig::default());
+        assert_eq!(replay_into(&mut engine, journal.as_bytes()).unwrap(), 2);
+        assert_eq!(engine.snapshot()[0].total.to_string(), "1");
+        assert!(matches!(
+            engine.apply(Transaction::withdrawal(1, 2, "1".parse().unwrap())),
+            TxOutcome::Rejected(_)
+        ));
+    }
+
+    #[test]
+    fn test_verify_tampered() {
+        let buffer = Shared::default();
+        let mut journal = Journal::from_writer(buffer.clone());
+        let mut engine = Engine::new(EngineConfig::default());
+        for tx in 1..4 {
+            let deposit = Transaction::deposit(1, tx, "1".parse().unwrap());
+            assert!(journal.apply(&mut engine, deposit).is_ok());
+        }
+        let written = buffer.text();
+        // The second deposit is edited after the fact.
+        let tampered = written.replacen("deposit,1,2,1,", "deposit,1,2,9,", 1);
+        assert!(matches!(
+            verify_journal(tampered.as_bytes()),
+            Err(FileError::BrokenChain { line: 5 })
+        ));
+        // Rows removed in the middle break the chain too.
+        let lines: Vec<_> = written.lines().collect();
+        let removed = [&lines[..3], &lines[5..]].concat().join("\n");
+        assert!(matches!(
+            verify_journal(removed.as_bytes()),
+            Err(FileError::BrokenChain { line: 4 })
+        ));
+    }
+
+    #[test]
+    fn test_verify_reformatted() {
+        let buffer = Shared::default();
+        let mut journal = Journal::from_writer(buffer.clone());
+        let mut engine = Engine::new(EngineConfig::default());
+        for tx in 1..4 {
+            let deposit = Transaction::deposit(1, tx, "1".parse().unwrap());
+            assert!(journal.apply(&mut engine, deposit).is_ok());
+        }
+        let written = buffer.text();
+        assert_eq!(verify_journal(written.as_bytes()).unwrap(), journal.head());
+        // Edits that keep the values still break the chain.
+        let rows: Vec<_> = written.lines().collect();
+        for edited in [
+            rows[3].replacen("deposit,1,2,1,", "deposit,1,2,1.0000,", 1),
+            format!("{},", rows[3]),
+        ] {
+            let tampered = written.replacen(rows[3], &edited, 1);
+            assert!(matches!(
+                verify_journal(tampered.as_bytes()),
+                Err(FileError::BrokenChain { line: 5 })
+            ));
+        }
+        // So do blank lines and line endings.
+        let blank = written.replacen(rows[3], &format!("{}\n", rows[3]), 1);
+        assert!(matches!(
+            verify_journal(blank.as_bytes()),
+            Err(FileError::BrokenChain { line: 5 })
+        ));
+        let crlf = written.replace('\n', "\r\n");
+        assert!(matches!(
+            verify_journal(crlf.as_bytes()),
+            Err(FileError::BrokenChain { line: 3 })
+        ));
+    }
+
+    #[test]
+    fn test_torn_row() {
+        let temp = TempPath::new("journal-torn.csv");
+        let path = temp.path();
+        let mut engine = Engine::new(EngineConfig::default());
+        {
+            let mut journal = Journal::open(&path).unwrap();
+            for tx in 1..4 {
+                let deposit = Transaction::deposit(1, tx, "1".parse().unwrap());
+                assert!(journal.apply(&mut engine, deposit).is_ok());
+            }
+        }
+        // A crash while writing the outcome of the last deposit.
+        let len = fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        assert!(file.set_len(len - 20).is_ok());
+        let mut journal = Journal::open(&path).unwrap();
+        let written = fs::read_to_string(&path).unwrap();
+        // The deposit itself was journaled, only its outcome is lost.
+        assert!(written.ends_with('\n'));
+        assert!(written
+            .lines()
+            .last()
+            .unwrap()
+            .starts_with("deposit,1,3,1,,"));
+        assert_eq!(verify_journal(written.as_bytes()).unwrap(), journal.head());
+        let deposit = Transaction::deposit(1, 4, "1".parse().unwrap());
+        assert!(journal.apply(&mut engine, deposit).is_ok());
+        let written = fs::read(&path).unwrap();
+        assert_eq!(verify_journal(written.as_slice()).unwrap(), journal.head());
+        let mut replayed = Engine::new(EngineConfig::default());
+        assert_eq!(replay_into(&mut replayed, written.as_slice()).unwrap(), 4);
+        assert_eq!(replayed.snapshot(), engine.snapshot());
+    }
+}
@@ -1,50 +1,415 @@
 //! Payment engine lib
+//!
+//! [`Engine`] applies transactions synchronously, without an async runtime.
+//! The channel based engine task and the csv entry points require the
+//! `runtime` feature (enabled by default), which pulls in tokio.
 
 mod engine;
 mod entities;
 mod errors;
 mod filehandler;
+mod generate;
+mod journal;
+#[cfg(feature = "runtime")]
+mod runner;
+#[cfg(feature = "runtime")]
+mod shard;
+mod snapshot;
+mod store;
+mod validate;
+mod wal;
 
 #[cfg(test)]
 mod tests;
 
-use std::io::stdout;
+#[cfg(feature = "runtime")]
+use std::fs::File;
+#[cfg(feature = "runtime")]
+use std::io::{stdout, Read, Write};
+use std::num::{NonZeroU64, NonZeroUsize};
+#[cfg(feature = "runtime")]
+use std::path::Path;
 
-use crate::engine::run;
-use crate::entities::channel::{create_engine_channel, Tx};
-use crate::entities::transaction::Transaction;
-use crate::entities::EngineEvent;
-use crate::errors::EngineError;
-use crate::filehandler::read_csv;
+#[cfg(feature = "runtime")]
+use tokio::io::AsyncRead;
+#[cfg(feature = "runtime")]
+use tokio::task::JoinHandle;
+#[cfg(feature = "runtime")]
+use tokio_util::io::SyncIoBridge;
+#[cfg(feature = "runtime")]
+use tracing::warn;
 
-/// TODO
+pub use crate::engine::Engine;
+pub use crate::entities::account::{Account, AccountSnapshot};
+pub use crate::entities::amount::Amount;
+#[cfg(feature = "runtime")]
+pub use crate::entities::channel::Tx;
+#[cfg(feature = "runtime")]
+use crate::entities::channel::{create_channel, create_engine_channel, Rx};
+pub use crate::entities::policy::{DisputePolicy, EngineConfig, HoldPolicy};
+#[cfg(feature = "runtime")]
+use crate::entities::rejection::Row;
+pub use crate::entities::transaction::{
+    Transaction, TransactionSnapshot, TransactionType, TxOutcome, TxState,
+};
+#[cfg(feature = "runtime")]
+pub use crate::entities::EngineEvent;
+pub use crate::errors::{
+    AccountError, AmountError, EngineError, FileError, StoreError,
+    TransactionError,
+};
+pub use crate::filehandler::InputFormat;
+#[cfg(feature = "runtime")]
+use crate::filehandler::{
+    csv_file_writer, open_input, transaction_rows, write_rejections, ParsedRow,
+};
+pub use crate::generate::{generate, Workload};
+pub use crate::journal::{
+    replay, replay_from, replay_into, verify_journal, Journal,
+};
+#[cfg(feature = "runtime")]
+use crate::runner::{run, Persistence};
+#[cfg(feature = "runtime")]
+pub use crate::runner::{DurableTx, EngineHandle, EngineSender};
+#[cfg(feature = "runtime")]
+use crate::shard::run_sharded;
+pub use crate::snapshot::{StateSnapshot, SNAPSHOT_VERSION};
+#[cfg(feature = "sqlite")]
+pub use crate::store::SqliteStore;
+pub use crate::store::{AccountStore, History, TransactionStore};
+pub use crate::validate::{
+    validate, validate_file, Issue, IssueKind, Validation,
+};
+pub use crate::wal::{SyncPolicy, Wal, WalConfig, DEFAULT_SEGMENT_BYTES};
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+/// How to treat rows that can not be parsed.
+pub enum ParseMode {
+    /// Log and count malformed rows, and continue with the next row.
+    /// Input that can not be read, or is not UTF-8, still aborts.
+    #[default]
+    Lenient,
+    /// Abort on the first malformed row.
+    Strict,
+}
+
+#[derive(Default, Debug)]
+/// Options for processing transaction input.
+pub struct RunConfig {
+    /// Format of the input.
+    pub format: InputFormat,
+    /// Path of the csv file to write the account report to, instead of stdout.
+    pub output: Option<String>,
+    /// Path of the csv file to write rejected transactions to, if any.
+    pub rejections: Option<String>,
+    /// How to treat malformed rows.
+    pub parse_mode: ParseMode,
+    /// Payment engine configuration.
+    pub engine: EngineConfig,
+    /// Number of engines to partition the accounts over, see [`run_stand_alone_sharded`].
+    /// `None` runs a single engine, as does writing rejections, since those
+    /// must follow the input order.
+    pub shards: Option<NonZeroUsize>,
+    /// Path of a SQLite database to persist accounts and transactions to,
+    /// processing resumes from its content. `None` keeps them in memory.
+    #[cfg(feature = "sqlite")]
+    pub database: Option<String>,
+    /// Path of the journal to append every transaction to, see [`Journal`].
+    /// The engine state is rebuilt from it first, unless `database` is set.
+    pub journal: Option<String>,
+    /// Path of the [`StateSnapshot`] to start from, if it exists, before
+    /// replaying the rest of the journal. Rewritten once the input is
+    /// processed. Ignored when `database` is set.
+    pub snapshot: Option<String>,
+    /// Writes a snapshot to `snapshot` every so many transactions.
+    pub snapshot_every: Option<NonZeroU64>,
+    /// Write-ahead log every transaction goes through before the engine
+    /// receives it, see [`Wal`]. Transactions after the snapshot and journal
+    /// are recovered from it first, segments they already hold are removed.
+    /// Ignored when `database` is set.
+    pub wal: Option<WalConfig>,
+}
+
+#[derive(Default, Debug)]
+/// Summary of processed csv input.
+pub struct RunSummary {
+    /// Number of rows skipped because they could not be parsed.
+    pub malformed: u64,
+}
+
+/// Processes the transactions in the csv file at `path`,
+/// and writes the resulting accounts to stdout.
+#[cfg(feature = "runtime")]
 pub async fn run_from_csv(path: &str) -> Result<(), EngineError> {
-    let mut content = read_csv(path)?;
+    run_from_csv_with(path, RunConfig::default()).await?;
+    Ok(())
+}
+
+#[cfg(feature = "runtime")]
+/// Same as [`run_from_csv`], with options, see [`run_from_reader`].
+/// The file extension must match `config.format`.
+pub async fn run_from_csv_with(
+    path: &str,
+    config: RunConfig,
+) -> Result<RunSummary, EngineError> {
+    let content = open_input(path, config.format)?;
+    run_from_reader(content, config).await
+}
+
+#[cfg(feature = "runtime")]
+/// Processes transactions in `config.format` from any reader, e.g. stdin,
+/// and writes the resulting accounts to stdout, or `config.output`.
+/// Every transaction that is not applied is written to `config.rejections`
+/// together with its line number and a reason code.
+/// Malformed rows are skipped unless `config.parse_mode` is [`ParseMode::Strict`].
+pub async fn run_from_reader<R: Read + Send + 'static>(
+    reader: R,
+    config: RunConfig,
+) -> Result<RunSummary, EngineError> {
+    let report: Box<dyn Write + Send> = match &config.output {
+        Some(path) => Box::new(File::create(path).map_err(FileError::StdOut)?),
+        None => Box::new(stdout()),
+    };
+    run_with_report(reader, config, report).await
+}
+
+#[cfg(feature = "runtime")]
+/// Same as [`run_from_reader`], writing the accounts to `report` instead.
+pub(crate) async fn run_with_report<R: Read + Send + 'static>(
+    reader: R,
+    config: RunConfig,
+    report: Box<dyn Write + Send>,
+) -> Result<RunSummary, EngineError> {
     let (transmit, recv) = create_engine_channel();
-    let payment_engine_handler = tokio::spawn(run(recv, stdout()));
-    for transaction in content.deserialize::<Transaction>() {
-        let tx = transaction?;
-        transmit.0.send(EngineEvent::Tx(tx)).await?; // TODO capture this.
+    let (outcomes, rejections) = match &config.rejections {
+        Some(rejection_path) => {
+            let stream = csv_file_writer(rejection_path)?;
+            let (outcome_transmit, outcome_recv) = create_channel();
+            let (row_transmit, row_recv) = create_channel::<Row>();
+            let writer =
+                tokio::spawn(write_rejections(outcome_recv, row_recv, stream));
+            (Some(outcome_transmit), Some((row_transmit, writer)))
+        }
+        None => (None, None),
+    };
+    let (payment_engine_handler, wal) =
+        spawn_engine(recv, outcomes, &config, report)?;
+    let sender = match wal {
+        Some(wal) => EngineSender::Durable(DurableTx::new(transmit, wal)),
+        None => EngineSender::Direct(transmit),
+    };
+    let (rows, writer) = rejections.unzip();
+    // Reading is blocking, keep it off the async workers.
+    let (parse_mode, format) = (config.parse_mode, config.format);
+    let fed = tokio::task::spawn_blocking(move || {
+        let send = |event| sender.blocking_send(event);
+        let content = transaction_rows(reader, format)?;
+        feed_transactions(content, send, rows, parse_mode)
+    })
+    .await?;
+    // An engine failure explains any failure to feed it, report it first.
+    payment_engine_handler
+        .await
+        .map_err(|e| EngineError::EngineCrashed(e.to_string()))??;
+    let summary = fed?;
+    if let Some(writer) = writer {
+        writer.await??;
     }
-    assert!(
-        transmit.0.send(EngineEvent::Report()).await.is_ok(),
-        "Unable to report to stdout."
-    );
-    assert!(
-        payment_engine_handler.await.is_ok(),
-        "Payment engine did not finish"
-    );
-    Ok(())
+    Ok(summary)
+}
+
+#[cfg(feature = "runtime")]
+/// Same as [`run_from_reader`], for asynchronous readers.
+pub async fn run_from_async_reader<R: AsyncRead + Unpin + Send + 'static>(
+    reader: R,
+    config: RunConfig,
+) -> Result<RunSummary, EngineError> {
+    run_from_reader(SyncIoBridge::new(reader), config).await
 }
 
+#[cfg(feature = "runtime")]
+/// Spawns the engine `config` asks for, reports are written to `report`.
+/// Returns the write-ahead log transactions must be sent through, if any.
+fn spawn_engine(
+    recv: Rx<EngineEvent>,
+    outcomes: Option<Tx<TxOutcome>>,
+    config: &RunConfig,
+    report: Box<dyn Write + Send>,
+) -> Result<(EngineTask, Option<Wal>), EngineError> {
+    let mut journal =
+        config.journal.as_deref().map(Journal::open).transpose()?;
+    #[cfg(feature = "sqlite")]
+    if let Some(database) = &config.database {
+        if config.shards.is_some() {
+            warn!("Persisting to a database, running a single engine");
+        }
+        if config.snapshot.is_some() || config.wal.is_some() {
+            warn!("Persisting to a database, snapshots and the write-ahead log are not used");
+        }
+        // The database holds the state, the journal is only appended to.
+        let engine = Engine::open_sqlite(config.engine, database)?;
+        let persistence = Persistence {
+            journal,
+            ..Default::default()
+        };
+        let task =
+            tokio::spawn(run(recv, report, outcomes, engine, persistence));
+        return Ok((task, None));
+    }
+    let persisted =
+        journal.is_some() || config.snapshot.is_some() || config.wal.is_some();
+    Ok(match (config.shards, outcomes, persisted) {
+        (Some(shards), None, false) => {
+            let task =
+                tokio::spawn(run_sharded(recv, report, config.engine, shards));
+            (task, None)
+        }
+        (shards, outcomes, _) => {
+            if shards.is_some() {
+                warn!("Rejections, a journal, snapshots or a write-ahead log are written, running a single engine");
+            }
+            let snapshot = &config.snapshot;
+            let mut engine = Engine::new(config.engine);
+            let (mut position, mut snapshotted) = (0, 0);
+            if let Some(path) = snapshot.as_deref() {
+                if Path::new(path).exists() {
+                    position =
+                        StateSnapshot::read(path)?.restore(&mut engine)?;
+                    snapshotted = position;
+                }
+            }
+            // Resume from the journal, it was just created if it did not exist.
+            if let Some(path) = &config.journal {
+                let file = File::open(path).map_err(FileError::Journal)?;
+                position = replay_from(&mut engine, file, position)?;
+            }
+            let mut wal = config.wal.clone().map(Wal::open).transpose()?;
+            if let Some(wal) = &mut wal {
+                position = match &mut journal {
+                    // Recovered transactions are journaled like new ones,
+                    // so journal positions keep matching the log.
+                    Some(journal) => wal.recover(position, |transaction| {
+                        journal.apply(&mut engine, transaction).map(drop)
+                    })?,
+                    None => wal.replay_into(&mut engine, position)?,
+                };
+                // The journal holds every recovered transaction, a snapshot
+                // only those before it.
+                let persisted = match journal {
+                    Some(_) => position,
+                    None => snapshotted,
+                };
+                wal.checkpoint(persisted)?;
+            }
+            let persistence = Persistence {
+                journal,
+                snapshot: snapshot.clone(),
+                snapshot_every: config.snapshot_every,
+                position,
+            };
+            let task =
+                tokio::spawn(run(recv, report, outcomes, engine, persistence));
+            (task, wal)
+        }
+    })
+}
+
+#[cfg(feature = "runtime")]
+/// The engine task, returning the final state of all accounts.
+type EngineTask = JoinHandle<Result<Vec<AccountSnapshot>, EngineError>>;
+
+#[cfg(feature = "runtime")]
+/// Sends every transaction in `content` to the engine, followed by a report
+/// and shutdown.
+/// Must be called outside of the async runtime, as it blocks on the channels.
+fn feed_transactions<F: Fn(EngineEvent) -> Result<(), EngineError>>(
+    content: impl Iterator<Item = ParsedRow>,
+    transmit: F,
+    rows: Option<Tx<Row>>,
+    parse_mode: ParseMode,
+) -> Result<RunSummary, EngineError> {
+    let mut summary = RunSummary::default();
+    for ParsedRow { line, raw, tx } in content {
+        let tx = match tx {
+            Ok(tx) => Some(tx),
+            Err(e)
+                if parse_mode == ParseMode::Lenient && e.is_malformed_row() =>
+            {
+                warn!(line, error = %e, "Skipping malformed row");
+                summary.malformed += 1;
+                None
+            }
+            Err(e) => return Err(e),
+        };
+        if let Some(rows) = &rows {
+            let row = Row::new(line, raw, tx.is_none());
+            rows.0
+                .blocking_send(row)
+                .map_err(|_| EngineError::RejectionSend)?;
+        }
+        if let Some(tx) = tx {
+            transmit(EngineEvent::Tx(tx))?;
+        }
+    }
+    if summary.malformed > 0 {
+        warn!(malformed = summary.malformed, "Skipped malformed rows");
+    }
+    for event in [EngineEvent::Report(), EngineEvent::Shutdown()] {
+        transmit(event).map_err(|_| {
+            EngineError::EngineCrashed(
+                "engine stopped before the report".into(),
+            )
+        })?;
+    }
+    Ok(summary)
+}
+
+#[cfg(feature = "runtime")]
 /// Starts the payment engine in standalone mode
-/// Continuously reads for transactions,
-/// and returns Tx for user to communicate with engine.
-pub async fn run_stand_alone() -> Result<Tx<EngineEvent>, EngineError> {
-    let (transmit, recv) = create_engine_channel();
-    assert!(
-        tokio::spawn(run(recv, stdout())).await.is_ok(),
-        "Unable to start engine!"
-    );
-    Ok(transmit)
+/// Continuously reads for transactions, reports are written to stdout,
+/// and returns a handle for user to communicate with engine.
+/// Must be called from within a tokio runtime.
+pub fn run_stand_alone(config: EngineConfig) -> EngineHandle {
+    EngineHandle::spawn(stdout(), Engine::new(config))
+}
+
+#[cfg(feature = "runtime")]
+/// Same as [`run_stand_alone`], on top of an engine with its own stores,
+/// see [`Engine::with_stores`].
+/// Must be called from within a tokio runtime.
+pub fn run_stand_alone_with<A, T>(engine: Engine<A, T>) -> EngineHandle
+where
+    A: AccountStore + Send + 'static,
+    T: TransactionStore + Send + 'static,
+{
+    EngineHandle::spawn(stdout(), engine)
+}
+
+#[cfg(feature = "runtime")]
+/// Same as [`run_stand_alone`], with accounts partitioned by client over
+/// `shards` engines running in parallel.
+/// Reports and snapshots are merged and ordered by client.
+/// Transaction ids must be unique across clients, reuse is only detected
+/// between clients on the same shard.
+/// Must be called from within a tokio runtime.
+pub fn run_stand_alone_sharded(
+    config: EngineConfig,
+    shards: NonZeroUsize,
+) -> EngineHandle {
+    EngineHandle::spawn_sharded(stdout(), config, shards)
+}
+
+#[cfg(feature = "runtime")]
+/// Same as [`run_stand_alone`], every transaction sent through the handle is
+/// logged to the write-ahead log first, see [`DurableTx`].
+/// The engine is recovered from the transactions already in the log.
+/// Must be called from within a tokio runtime.
+pub fn run_stand_alone_durable(
+    config: EngineConfig,
+    wal: WalConfig,
+) -> Result<EngineHandle, EngineError> {
+    let mut wal = Wal::open(wal)?;
+    let mut engine = Engine::new(config);
+    let position = wal.replay_into(&mut engine, 0)?;
+    Ok(EngineHandle::spawn_durable(stdout(), engine, wal, position))
 }
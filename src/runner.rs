@@ -0,0 +1,347 @@
+//! Channel based payment engine task.
+//!
+//! A thin async wrapper around [`Engine`], feeding it events from a channel.
+use crate::engine::Engine;
+use crate::entities::account::AccountSnapshot;
+use crate::entities::channel::{create_engine_channel, Rx, Tx};
+use crate::entities::policy::EngineConfig;
+use crate::entities::transaction::{TransactionSnapshot, TxOutcome};
+use crate::entities::EngineEvent;
+use crate::errors::EngineError;
+use crate::journal::Journal;
+use crate::shard::run_sharded;
+use crate::snapshot::StateSnapshot;
+use crate::store::{AccountStore, TransactionStore};
+use crate::wal::{SyncPolicy, Wal};
+use std::io::Write;
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{oneshot, Mutex};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+#[derive(Default)]
+/// How the engine task persists what it receives.
+pub(crate) struct Persistence {
+    /// Every transaction is journaled before it is applied.
+    pub(crate) journal: Option<Journal>,
+    /// A snapshot is written to the path when the task stops.
+    pub(crate) snapshot: Option<String>,
+    /// And every so many transactions, if set.
+    pub(crate) snapshot_every: Option<NonZeroU64>,
+    /// Transactions received before the task started, e.g. restored from a
+    /// snapshot and the journal.
+    pub(crate) position: u64,
+}
+
+/// Runs the payment engine, reports are written to `report_stream`.
+/// If `outcomes` is provided, the outcome of every transaction is sent on it, in order.
+/// Stops on [`EngineEvent::Shutdown`] or once every sender is dropped,
+/// writes the final snapshot, if any, and returns the final state of all
+/// accounts.
+pub(crate) async fn run<S: Write, A: AccountStore, T: TransactionStore>(
+    mut rx: Rx<EngineEvent>,
+    mut report_stream: S,
+    mut outcomes: Option<Tx<TxOutcome>>,
+    mut engine: Engine<A, T>,
+    mut persistence: Persistence,
+) -> Result<Vec<AccountSnapshot>, EngineError> {
+    while let Some(event) = rx.receive.recv().await
+    // Blocking recv, could go for polling as well.
+    {
+        match event {
+            EngineEvent::Tx(e) => {
+                let (tx, client) = (e.tx, e.client);
+                let outcome = match &mut persistence.journal {
+                    Some(journal) => journal.apply(&mut engine, e)?,
+                    None => engine.apply(e),
+                };
+                persistence.position += 1;
+                if let (Some(path), Some(every)) =
+                    (&persistence.snapshot, persistence.snapshot_every)
+                {
+                    if persistence.position.is_multiple_of(every.get()) {
+                        let snapshot =
+                            StateSnapshot::of(&engine, persistence.position);
+                        if let Err(e) = snapshot.write(path) {
+                            warn!(path, error = %e, "Unable to write snapshot");
+                        }
+                    }
+                }
+                if let TxOutcome::Rejected(reason) = &outcome {
+                    info!(tx, client, %reason, "Rejected transaction");
+                }
+                if let Some(sender) = &outcomes {
+                    if sender.0.send(outcome).await.is_err() {
+                        outcomes = None; // Receiver is gone, stop reporting outcomes.
+                    }
+                }
+            }
+            EngineEvent::Report() => {
+                let head = persistence.journal.as_ref().map(Journal::head);
+                engine.report_with_head(&mut report_stream, head.as_deref())?;
+            }
+            // The requester may have given up waiting, nothing to do then.
+            EngineEvent::Snapshot(reply) => {
+                let _ = reply.send(engine.snapshot());
+            }
+            EngineEvent::GetAccount { client, reply } => {
+                let _ = reply.send(engine.account(client));
+            }
+            EngineEvent::GetTransaction { tx, reply } => {
+                let _ = reply.send(engine.transaction(tx));
+            }
+            EngineEvent::WriteSnapshot { path, reply } => {
+                let snapshot = StateSnapshot::of(&engine, persistence.position);
+                let _ = reply.send(snapshot.write(&path));
+            }
+            EngineEvent::Shutdown() => break,
+        }
+    }
+    // Without it, the transactions after the last periodic snapshot are lost
+    // when there is no journal to replay them from.
+    if let Some(path) = &persistence.snapshot {
+        StateSnapshot::of(&engine, persistence.position).write(path)?;
+    }
+    Ok(engine.snapshot())
+}
+
+#[derive(Clone)]
+/// Sender that appends every transaction to a write-ahead log before the
+/// engine receives it, see [`Wal`].
+/// Once a send returns, the transaction is logged, and synced to disk as the
+/// [`SyncPolicy`] says.
+pub struct DurableTx {
+    sender: Tx<EngineEvent>,
+    wal: Arc<Mutex<Wal>>,
+}
+
+impl DurableTx {
+    /// Logs the transactions sent on `sender` to `wal`.
+    /// With [`SyncPolicy::Interval`] a task syncs the log every interval,
+    /// until every clone is dropped, so must be called from within a tokio
+    /// runtime.
+    pub(crate) fn new(sender: Tx<EngineEvent>, wal: Wal) -> Self {
+        let policy = wal.policy();
+        let wal = Arc::new(Mutex::new(wal));
+        if let SyncPolicy::Interval(interval) = policy {
+            let wal = Arc::downgrade(&wal);
+            tokio::spawn(async move {
+                let mut ticks = tokio::time::interval(
+                    interval.max(Duration::from_millis(1)),
+                );
+                loop {
+                    ticks.tick().await;
+                    let Some(wal) = wal.upgrade() else { break };
+                    let synced = wal.lock().await.sync();
+                    if let Err(e) = synced {
+                        warn!(error = %e, "Unable to sync write-ahead log");
+                    }
+                }
+            });
+        }
+        DurableTx { sender, wal }
+    }
+
+    /// Sends an event to the engine, logging it first if it is a transaction.
+    /// Nothing is sent if it can not be logged.
+    pub async fn send(&self, event: EngineEvent) -> Result<(), EngineError> {
+        // Held until sent, so the log and the engine see the same order.
+        let mut wal = self.wal.lock().await;
+        if let EngineEvent::Tx(transaction) = &event {
+            wal.append(transaction)?;
+        }
+        Ok(self.sender.0.send(event).await?)
+    }
+
+    /// Same as [`DurableTx::send`], blocking the current thread.
+    /// Must be called outside of the async runtime.
+    pub fn blocking_send(&self, event: EngineEvent) -> Result<(), EngineError> {
+        let mut wal = self.wal.blocking_lock();
+        if let EngineEvent::Tx(transaction) = &event {
+            wal.append(transaction)?;
+        }
+        Ok(self.sender.0.blocking_send(event)?)
+    }
+}
+
+#[derive(Clone)]
+/// Sender for events to an engine, see [`EngineHandle::sender`].
+pub enum EngineSender {
+    /// Straight to the engine.
+    Direct(Tx<EngineEvent>),
+    /// Through the write-ahead log of the engine.
+    Durable(DurableTx),
+}
+
+impl EngineSender {
+    /// Sends an event to the engine, through the write-ahead log if any.
+    pub async fn send(&self, event: EngineEvent) -> Result<(), EngineError> {
+        match self {
+            EngineSender::Direct(sender) => Ok(sender.0.send(event).await?),
+            EngineSender::Durable(durable) => durable.send(event).await,
+        }
+    }
+
+    /// Same as [`EngineSender::send`], blocking the current thread.
+    /// Must be called outside of the async runtime.
+    pub fn blocking_send(&self, event: EngineEvent) -> Result<(), EngineError> {
+        match self {
+            EngineSender::Direct(sender) => {
+                Ok(sender.0.blocking_send(event)?)
+            }
+            EngineSender::Durable(durable) => durable.blocking_send(event),
+        }
+    }
+
+    /// The channel to the engine, bypassing the write-ahead log.
+    fn channel(&self) -> &Tx<EngineEvent> {
+        match self {
+            EngineSender::Direct(sender) => sender,
+            EngineSender::Durable(durable) => &durable.sender,
+        }
+    }
+}
+
+/// Handle to a payment engine running as a tokio task.
+pub struct EngineHandle {
+    sender: EngineSender,
+    handle: JoinHandle<Result<Vec<AccountSnapshot>, EngineError>>,
+}
+
+impl EngineHandle {
+    /// Spawns a payment engine, reports are written to `report_stream`.
+    /// Must be called from within a tokio runtime.
+    pub(crate) fn spawn<S, A, T>(report_stream: S, engine: Engine<A, T>) -> Self
+    where
+        S: Write + Send + 'static,
+        A: AccountStore + Send + 'static,
+        T: TransactionStore + Send + 'static,
+    {
+        let (sender, recv) = create_engine_channel();
+        let handle = tokio::spawn(run(
+            recv,
+            report_stream,
+            None,
+            engine,
+            Persistence::default(),
+        ));
+        EngineHandle {
+            sender: EngineSender::Direct(sender),
+            handle,
+        }
+    }
+
+    /// Same as [`EngineHandle::spawn`], every transaction sent through the
+    /// handle is logged to `wal` first.
+    /// `position` is the number of transactions `engine` already received,
+    /// see [`Wal::replay_into`].
+    pub(crate) fn spawn_durable<S, A, T>(
+        report_stream: S,
+        engine: Engine<A, T>,
+        wal: Wal,
+        position: u64,
+    ) -> Self
+    where
+        S: Write + Send + 'static,
+        A: AccountStore + Send + 'static,
+        T: TransactionStore + Send + 'static,
+    {
+        let (sender, recv) = create_engine_channel();
+        let persistence = Persistence {
+            position,
+            ..Default::default()
+        };
+        let handle =
+            tokio::spawn(run(recv, report_stream, None, engine, persistence));
+        EngineHandle {
+            sender: EngineSender::Durable(DurableTx::new(sender, wal)),
+            handle,
+        }
+    }
+
+    /// Same as [`EngineHandle::spawn`], with accounts partitioned by client
+    /// over `shards` engines running in parallel.
+    pub(crate) fn spawn_sharded<S: Write + Send + 'static>(
+        report_stream: S,
+        config: EngineConfig,
+        shards: NonZeroUsize,
+    ) -> Self {
+        let (sender, recv) = create_engine_channel();
+        let handle =
+            tokio::spawn(run_sharded(recv, report_stream, config, shards));
+        EngineHandle {
+            sender: EngineSender::Direct(sender),
+            handle,
+        }
+    }
+
+    /// Returns a sender for events, it can be cloned and shared between tasks.
+    /// Transactions sent on it go through the write-ahead log, if any.
+    #[must_use]
+    pub fn sender(&self) -> EngineSender {
+        self.sender.clone()
+    }
+
+    /// Sends an event to the engine, through the write-ahead log if any.
+    pub async fn send(&self, event: EngineEvent) -> Result<(), EngineError> {
+        self.sender.send(event).await
+    }
+
+    /// Returns the current state of all accounts, ordered by client,
+    /// after every event sent before this call is processed.
+    pub async fn snapshot(&self) -> Result<Vec<AccountSnapshot>, EngineError> {
+        let (reply, response) = oneshot::channel();
+        self.send(EngineEvent::Snapshot(reply)).await?;
+        response
+            .await
+            .map_err(|e| EngineError::EngineCrashed(e.to_string()))
+    }
+
+    /// Returns the current state of a single account, if it exists.
+    pub async fn account(
+        &self,
+        client: u16,
+    ) -> Result<Option<AccountSnapshot>, EngineError> {
+        let (reply, response) = oneshot::channel();
+        self.send(EngineEvent::GetAccount { client, reply }).await?;
+        response
+            .await
+            .map_err(|e| EngineError::EngineCrashed(e.to_string()))
+    }
+
+    /// Returns a stored deposit or withdrawal, if it exists.
+    pub async fn transaction(
+        &self,
+        tx: u32,
+    ) -> Result<Option<TransactionSnapshot>, EngineError> {
+        let (reply, response) = oneshot::channel();
+        self.send(EngineEvent::GetTransaction { tx, reply }).await?;
+        response
+            .await
+            .map_err(|e| EngineError::EngineCrashed(e.to_string()))
+    }
+
+    /// Writes a snapshot of the full engine state to `path`, after every
+    /// event sent before this call is processed.
+    pub async fn write_snapshot(&self, path: &str) -> Result<(), EngineError> {
+        let (reply, response) = oneshot::channel();
+        let path = path.to_string();
+        self.send(EngineEvent::WriteSnapshot { path, reply })
+            .await?;
+        Ok(response
+            .await
+            .map_err(|e| EngineError::EngineCrashed(e.to_string()))??)
+    }
+
+    /// Stops the engine after every event sent before this call is processed,
+    /// and returns the final state of all accounts, ordered by client.
+    pub async fn shutdown(self) -> Result<Vec<AccountSnapshot>, EngineError> {
+        // The engine may already have stopped, e.g. if every sender was dropped.
+        let _ = self.sender.channel().0.send(EngineEvent::Shutdown()).await;
+        self.handle
+            .await
+            .map_err(|e| EngineError::EngineCrashed(e.to_string()))?
+    }
+}
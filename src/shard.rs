@@ -0,0 +1,168 @@
+//! Client-sharded payment engine.
+//!
+//! Accounts never interact, so every client is owned by exactly one shard,
+//! a regular payment engine task with its own accounts and transaction history.
+//! A router task forwards each event to the shard(s) it concerns.
+//!
+//! Every shard only knows its own transaction ids, so the router remembers
+//! which shard last received each deposit or withdrawal id. When a reused id
+//! is routed to another shard, the router asks the shards whether the id is
+//! stored, and if so forwards the transaction without its amount, a single
+//! engine would reject it as a duplicate without touching any balance.
+//! The shard logs it as `missing_amount` rather than `duplicate_tx`, only
+//! the accounts are the same as those of a single engine.
+use crate::engine::Engine;
+use crate::entities::account::AccountSnapshot;
+use crate::entities::channel::{create_engine_channel, Rx, Tx};
+use crate::entities::policy::EngineConfig;
+use crate::entities::transaction::{TransactionSnapshot, TransactionType};
+use crate::entities::EngineEvent;
+use crate::errors::{EngineError, FileError};
+use crate::filehandler::csv_to_stdout;
+use crate::runner::{run, Persistence};
+use std::collections::HashMap;
+use std::io::{self, sink, Write};
+use std::num::NonZeroUsize;
+use tokio::sync::oneshot;
+
+/// Routes events to `shards` payment engines, partitioned by client.
+/// Reports, snapshots and the returned final state are merged and ordered by
+/// client, so the output does not depend on the number of shards, even when
+/// deposits or withdrawals reuse the ids of other clients.
+/// Stops on [`EngineEvent::Shutdown`], or once every sender is dropped.
+pub(crate) async fn run_sharded<S: Write>(
+    mut rx: Rx<EngineEvent>,
+    mut report_stream: S,
+    config: EngineConfig,
+    shards: NonZeroUsize,
+) -> Result<Vec<AccountSnapshot>, EngineError> {
+    let (senders, handles): (Vec<_>, Vec<_>) = (0..shards.get())
+        .map(|_| {
+            let (sender, recv) = create_engine_channel();
+            (
+                sender,
+                tokio::spawn(run(
+                    recv,
+                    sink(),
+                    None,
+                    Engine::new(config),
+                    Persistence::default(),
+                )),
+            )
+        })
+        .unzip();
+    let index = |client: u16| usize::from(client) % shards;
+    let shard = |client: u16| &senders[index(client)];
+    // Shard that last stored, or may have stored, each deposit or withdrawal id.
+    let mut claims: HashMap<u32, usize> = HashMap::new();
+
+    while let Some(event) = rx.receive.recv().await {
+        match event {
+            EngineEvent::Tx(mut e) => {
+                if matches!(
+                    e.typename,
+                    TransactionType::Deposit | TransactionType::Withdrawal
+                ) {
+                    let target = index(e.client);
+                    let elsewhere = match claims.get(&e.tx) {
+                        Some(&last) if last != target => {
+                            transaction(&senders, e.tx).await?.is_some_and(
+                                |stored| index(stored.client) != target,
+                            )
+                        }
+                        _ => false,
+                    };
+                    if elsewhere {
+                        // Rejected for its missing amount, it still opens
+                        // the account, like a duplicate in a single engine.
+                        // Only the logged reason differs, see the module.
+                        e.amount = None;
+                    } else {
+                        claims.insert(e.tx, target);
+                    }
+                }
+                shard(e.client).0.send(EngineEvent::Tx(e)).await?;
+            }
+            EngineEvent::Report() => {
+                csv_to_stdout(
+                    &snapshot(&senders).await?,
+                    None,
+                    &mut report_stream,
+                )?;
+            }
+            // The requester may have given up waiting, nothing to do then.
+            EngineEvent::Snapshot(reply) => {
+                let _ = reply.send(snapshot(&senders).await?);
+            }
+            EngineEvent::GetAccount { client, reply } => {
+                shard(client)
+                    .0
+                    .send(EngineEvent::GetAccount { client, reply })
+                    .await?;
+            }
+            EngineEvent::GetTransaction { tx, reply } => {
+                let _ = reply.send(transaction(&senders, tx).await?);
+            }
+            EngineEvent::WriteSnapshot { reply, .. } => {
+                let unsupported = io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "the sharded engine does not write snapshots",
+                );
+                let _ = reply.send(Err(FileError::Snapshot(unsupported)));
+            }
+            EngineEvent::Shutdown() => break,
+        }
+    }
+    // Closing the channels stops the shards once they are drained.
+    drop(senders);
+    let mut accounts = Vec::new();
+    for handle in handles {
+        accounts.extend(
+            handle
+                .await
+                .map_err(|e| EngineError::EngineCrashed(e.to_string()))??,
+        );
+    }
+    accounts.sort_by_key(|a| a.client);
+    Ok(accounts)
+}
+
+/// Asks `shard` a question, and waits for the answer.
+async fn ask<T>(
+    shard: &Tx<EngineEvent>,
+    event: impl FnOnce(oneshot::Sender<T>) -> EngineEvent,
+) -> Result<T, EngineError> {
+    let (reply, response) = oneshot::channel();
+    shard.0.send(event(reply)).await?;
+    response
+        .await
+        .map_err(|e| EngineError::EngineCrashed(e.to_string()))
+}
+
+/// Merges the accounts of every shard, ordered by client.
+async fn snapshot(
+    shards: &[Tx<EngineEvent>],
+) -> Result<Vec<AccountSnapshot>, EngineError> {
+    let mut accounts = Vec::new();
+    for shard in shards {
+        accounts.extend(ask(shard, EngineEvent::Snapshot).await?);
+    }
+    accounts.sort_by_key(|a| a.client);
+    Ok(accounts)
+}
+
+/// Looks `tx` up in every shard, as its owner is unknown.
+async fn transaction(
+    shards: &[Tx<EngineEvent>],
+    tx: u32,
+) -> Result<Option<TransactionSnapshot>, EngineError> {
+    for shard in shards {
+        let found =
+            ask(shard, |reply| EngineEvent::GetTransaction { tx, reply })
+                .await?;
+        if found.is_some() {
+            return Ok(found);
+        }
+    }
+    Ok(None)
+}
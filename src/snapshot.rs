@@ -0,0 +1,137 @@
+//! Versioned snapshots of the full engine state.
+//!
+//! A snapshot holds every account and stored transaction, and how many
+//! transactions the engine had received when it was taken, its position in
+//! the journal. Restoring a snapshot and replaying the journal from that
+//! position rebuilds the engine without replaying the whole journal.
+//!
+//! Snapshots are json, fields added in later versions must have a default so
+//! older snapshots can still be read.
+use crate::engine::Engine;
+use crate::entities::account::AccountSnapshot;
+use crate::entities::transaction::TransactionSnapshot;
+use crate::errors::FileError;
+use crate::store::{AccountStore, TransactionStore};
+use serde::{Deserialize, Serialize};
+use std::fs::{rename, File};
+use std::io::{BufReader, BufWriter, Write};
+
+/// Snapshot format written by this build.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+/// Full engine state at a point in time.
+pub struct StateSnapshot {
+    /// Format version, see [`SNAPSHOT_VERSION`].
+    pub version: u32,
+    /// Number of transactions the engine received before the snapshot.
+    pub position: u64,
+    /// Every account, ordered by client.
+    pub accounts: Vec<AccountSnapshot>,
+    /// Every stored deposit and withdrawal, ordered by id.
+    pub transactions: Vec<TransactionSnapshot>,
+}
+
+impl StateSnapshot {
+    /// Takes a snapshot of `engine`, which received `position` transactions.
+    pub fn of<A: AccountStore, T: TransactionStore>(
+        engine: &Engine<A, T>,
+        position: u64,
+    ) -> Self {
+        StateSnapshot {
+            version: SNAPSHOT_VERSION,
+            position,
+            accounts: engine.snapshot(),
+            transactions: engine.transactions(),
+        }
+    }
+
+    /// Writes the snapshot to `path`, replacing it at once so a crash never
+    /// leaves a partial snapshot behind.
+    pub fn write(&self, path: &str) -> Result<(), FileError> {
+        let partial = format!("{path}.partial");
+        let file = File::create(&partial).map_err(FileError::Snapshot)?;
+        let mut wtr = BufWriter::new(file);
+        serde_json::to_writer(&mut wtr, self)?;
+        wtr.flush().map_err(FileError::Snapshot)?;
+        wtr.get_ref().sync_all().map_err(FileError::Snapshot)?;
+        rename(&partial, path).map_err(FileError::Snapshot)
+    }
+
+    /// Reads the snapshot at `path`.
+    /// Fails if it was written by a newer version.
+    pub fn read(path: &str) -> Result<Self, FileError> {
+        let file = File::open(path).map_err(FileError::Snapshot)?;
+        let snapshot: StateSnapshot =
+            serde_json::from_reader(BufReader::new(file))?;
+        if snapshot.version > SNAPSHOT_VERSION {
+            return Err(FileError::SnapshotVersion {
+                found: snapshot.version,
+                supported: SNAPSHOT_VERSION,
+            });
+        }
+        Ok(snapshot)
+    }
+
+    /// Stores every account and transaction of the snapshot in `engine`,
+    /// returning the snapshot position.
+    pub fn restore<A: AccountStore, T: TransactionStore>(
+        &self,
+        engine: &mut Engine<A, T>,
+    ) -> Result<u64, FileError> {
+        engine.load(&self.accounts, &self.transactions)?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StateSnapshot, SNAPSHOT_VERSION};
+    use crate::engine::Engine;
+    use crate::entities::policy::EngineConfig;
+    use crate::entities::transaction::{Transaction, TxOutcome};
+    use crate::errors::FileError;
+    use crate::tests::TempPath;
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut engine = Engine::new(EngineConfig::default());
+        let amount = "1.5".parse().unwrap();
+        for event in [
+            Transaction::deposit(2, 1, amount),
+            Transaction::deposit(1, 2, amount),
+            Transaction::dispute(2, 1),
+        ] {
+            assert!(matches!(engine.apply(event), TxOutcome::Applied));
+        }
+        let temp = TempPath::new("round-trip.json");
+        let path = temp.path();
+        assert!(StateSnapshot::of(&engine, 3).write(&path).is_ok());
+        let snapshot = StateSnapshot::read(&path);
+        assert!(snapshot.is_ok());
+        let snapshot = snapshot.unwrap();
+        assert_eq!(snapshot.version, SNAPSHOT_VERSION);
+        let mut restored = Engine::new(EngineConfig::default());
+        assert_eq!(snapshot.restore(&mut restored).unwrap(), 3);
+        assert_eq!(restored.snapshot(), engine.snapshot());
+        assert_eq!(restored.transactions(), engine.transactions());
+        // The restored history still guards against duplicates.
+        assert!(matches!(
+            restored.apply(Transaction::deposit(1, 2, amount)),
+            TxOutcome::Rejected(_)
+        ));
+    }
+
+    #[test]
+    fn test_newer_version() {
+        let temp = TempPath::new("newer.json");
+        let path = temp.path();
+        let snapshot =
+            r#"{"version":99,"position":0,"accounts":[],"transactions":[]}"#;
+        assert!(std::fs::write(&path, snapshot).is_ok());
+        assert!(matches!(
+            StateSnapshot::read(&path),
+            Err(FileError::SnapshotVersion { found: 99, .. })
+        ));
+    }
+}
@@ -0,0 +1,215 @@
+//! Storage for accounts and the transaction history.
+//!
+//! [`Engine`](crate::Engine) is generic over these traits, the in-memory
+//! `HashMap`s are the default implementation.
+//! Stores work on owned values, the engine reads a record, changes it, and
+//! writes it back, so a backend does not have to keep records in memory.
+//! After every transaction the engine calls `commit` on both stores, a
+//! persistent backend makes the writes of that transaction durable at once.
+//! Backends outside the crate persist [`Account`] as is, or as an
+//! [`AccountSnapshot`](crate::AccountSnapshot), and [`History`] as the
+//! [`TransactionSnapshot`] of [`History::snapshot`].
+use crate::entities::account::Account;
+use crate::entities::amount::Amount;
+use crate::entities::transaction::{
+    TransactionSnapshot, TransactionType, TxState,
+};
+use crate::errors::StoreError;
+use std::collections::HashMap;
+
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStore;
+
+#[derive(Debug, Clone)]
+/// A stored deposit or withdrawal, no need to store the entire transaction.
+pub struct History {
+    /// Whether this was a deposit or a withdrawal.
+    pub(crate) kind: TransactionType,
+    /// Amount of the transaction.
+    pub(crate) amount: Amount,
+    /// The client that created the transaction.
+    pub(crate) client: u16,
+    /// Where the transaction is in the dispute lifecycle.
+    pub(crate) state: TxState,
+}
+impl History {
+    /// A deposit or withdrawal of `client`, not disputed.
+    pub fn new(kind: TransactionType, amount: Amount, client: u16) -> Self {
+        History {
+            kind,
+            amount,
+            client,
+            state: TxState::Processed,
+        }
+    }
+
+    /// Point in time copy of transaction `tx`, see also
+    /// `From<&TransactionSnapshot>` to read one back.
+    #[must_use]
+    pub fn snapshot(&self, tx: u32) -> TransactionSnapshot {
+        TransactionSnapshot {
+            tx,
+            typename: self.kind,
+            client: self.client,
+            amount: self.amount,
+            state: self.state,
+        }
+    }
+}
+impl From<&TransactionSnapshot> for History {
+    fn from(snapshot: &TransactionSnapshot) -> Self {
+        History {
+            kind: snapshot.typename,
+            amount: snapshot.amount,
+            client: snapshot.client,
+            state: snapshot.state,
+        }
+    }
+}
+
+/// Storage for client accounts.
+pub trait AccountStore {
+    /// Returns the account of `client`, if it exists.
+    fn get(&self, client: u16) -> Option<Account>;
+    /// Inserts or replaces the account of `client`.
+    fn insert(&mut self, client: u16, account: Account);
+    /// Returns every account, in any order.
+    fn all(&self) -> Vec<Account>;
+    /// Makes the writes since the last commit durable.
+    fn commit(&mut self) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+/// Storage for deposits and withdrawals, by transaction id.
+pub trait TransactionStore {
+    /// Returns transaction `tx`, if it exists.
+    fn get(&self, tx: u32) -> Option<History>;
+    /// Inserts or replaces transaction `tx`.
+    fn insert(&mut self, tx: u32, history: History);
+    /// Returns every transaction with its id, in any order.
+    fn all(&self) -> Vec<(u32, History)>;
+    /// Whether transaction `tx` exists.
+    fn contains(&self, tx: u32) -> bool {
+        self.get(tx).is_some()
+    }
+    /// Makes the writes since the last commit durable.
+    fn commit(&mut self) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+impl AccountStore for HashMap<u16, Account> {
+    fn get(&self, client: u16) -> Option<Account> {
+        HashMap::get(self, &client).cloned()
+    }
+    fn insert(&mut self, client: u16, account: Account) {
+        HashMap::insert(self, client, account);
+    }
+    fn all(&self) -> Vec<Account> {
+        self.values().cloned().collect()
+    }
+}
+
+impl TransactionStore for HashMap<u32, History> {
+    fn get(&self, tx: u32) -> Option<History> {
+        HashMap::get(self, &tx).cloned()
+    }
+    fn insert(&mut self, tx: u32, history: History) {
+        HashMap::insert(self, tx, history);
+    }
+    fn all(&self) -> Vec<(u32, History)> {
+        self.iter().map(|(tx, h)| (*tx, h.clone())).collect()
+    }
+    fn contains(&self, tx: u32) -> bool {
+        self.contains_key(&tx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Only the public API, as a store outside the crate would be written.
+    use crate::{
+        Account, AccountSnapshot, AccountStore, Engine, EngineConfig, History,
+        Transaction, TransactionSnapshot, TransactionStore, TransactionType,
+        TxOutcome, TxState,
+    };
+    use std::collections::BTreeMap;
+
+    /// Accounts kept serialized, like a persistent backend.
+    #[derive(Default)]
+    struct JsonAccounts(BTreeMap<u16, String>);
+    impl AccountStore for JsonAccounts {
+        fn get(&self, client: u16) -> Option<Account> {
+            serde_json::from_str(self.0.get(&client)?).ok()
+        }
+        fn insert(&mut self, client: u16, account: Account) {
+            let json = serde_json::to_string(&account).unwrap();
+            self.0.insert(client, json);
+        }
+        fn all(&self) -> Vec<Account> {
+            self.0
+                .keys()
+                .filter_map(|c| AccountStore::get(self, *c))
+                .collect()
+        }
+    }
+
+    /// Transactions kept serialized as snapshots.
+    #[derive(Default)]
+    struct JsonTransactions(BTreeMap<u32, String>);
+    impl TransactionStore for JsonTransactions {
+        fn get(&self, tx: u32) -> Option<History> {
+            let json = self.0.get(&tx)?;
+            let snapshot: TransactionSnapshot =
+                serde_json::from_str(json).ok()?;
+            Some(History::from(&snapshot))
+        }
+        fn insert(&mut self, tx: u32, history: History) {
+            let json = serde_json::to_string(&history.snapshot(tx)).unwrap();
+            self.0.insert(tx, json);
+        }
+        fn all(&self) -> Vec<(u32, History)> {
+            self.0
+                .keys()
+                .filter_map(|tx| Some((*tx, TransactionStore::get(self, *tx)?)))
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_external_store() {
+        let amount = "1.5".parse().unwrap();
+        // Seeded with a deposit made before the engine started.
+        let mut accounts = JsonAccounts::default();
+        let seeded = AccountSnapshot {
+            client: 1,
+            available: amount,
+            held: "0".parse().unwrap(),
+            total: amount,
+            locked: false,
+        };
+        accounts.insert(1, Account::from(&seeded));
+        let mut transactions = JsonTransactions::default();
+        let deposit = History::new(TransactionType::Deposit, amount, 1);
+        transactions.insert(1, deposit);
+        let mut engine = Engine::with_stores(
+            EngineConfig::default(),
+            accounts,
+            transactions,
+        );
+        for tx in [
+            Transaction::deposit(1, 2, amount),
+            Transaction::dispute(1, 1),
+        ] {
+            assert!(matches!(engine.apply(tx), TxOutcome::Applied));
+        }
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.total.to_string(), "3");
+        assert_eq!(account.held, amount);
+        let disputed = engine.transaction(1).unwrap();
+        assert_eq!((disputed.client, disputed.state), (1, TxState::Disputed));
+    }
+}
@@ -0,0 +1,375 @@
+//! SQLite backed stores, enabled with the `sqlite` feature.
+//!
+//! A single [`SqliteStore`] serves as both account and transaction store,
+//! the engine holds two clones sharing one connection. Writes open a
+//! database transaction, the engine commits it once per applied event, so
+//! an event is either fully persisted or not at all.
+use super::{AccountStore, History, TransactionStore};
+use crate::engine::Engine;
+use crate::entities::account::Account;
+use crate::entities::amount::Amount;
+use crate::entities::policy::EngineConfig;
+use crate::entities::transaction::{TransactionType, TxState};
+use crate::errors::StoreError;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+    CREATE TABLE IF NOT EXISTS accounts (
+        client    INTEGER PRIMARY KEY,
+        available INTEGER NOT NULL,
+        held      INTEGER NOT NULL,
+        total     INTEGER NOT NULL,
+        locked    INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS transactions (
+        tx     INTEGER PRIMARY KEY,
+        kind   TEXT NOT NULL,
+        client INTEGER NOT NULL,
+        amount INTEGER NOT NULL,
+        state  TEXT NOT NULL
+    );
+";
+
+struct Inner {
+    conn: Connection,
+    /// Whether a database transaction is open.
+    pending: bool,
+    /// First failure since the last commit, the transaction is rolled back.
+    error: Option<StoreError>,
+}
+
+impl Inner {
+    /// Records `result`'s failure, to be reported by the next commit.
+    fn check<R>(&mut self, result: Result<R, StoreError>) -> Option<R> {
+        match result {
+            Ok(r) => Some(r),
+            Err(e) => {
+                self.error.get_or_insert(e);
+                None
+            }
+        }
+    }
+
+    /// Opens a database transaction, unless one is open already.
+    fn begin(&mut self) -> Result<(), StoreError> {
+        if !self.pending {
+            self.conn.execute_batch("BEGIN")?;
+            self.pending = true;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+/// Accounts and transaction history persisted in a SQLite database file.
+pub struct SqliteStore {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl SqliteStore {
+    /// Opens the database at `path`, creating it and its tables if needed.
+    /// Existing accounts and transactions are kept.
+    pub fn open(path: &str) -> Result<Self, StoreError> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(SqliteStore {
+            inner: Arc::new(Mutex::new(Inner {
+                conn,
+                pending: false,
+                error: None,
+            })),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        // A panic while holding the lock aborts, see the release profile.
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Shared by both [`AccountStore::commit`] and [`TransactionStore::commit`],
+    /// the second call finds nothing left to commit.
+    fn commit(&mut self) -> Result<(), StoreError> {
+        let mut inner = self.lock();
+        let error = inner.error.take();
+        if inner.pending {
+            inner.pending = false;
+            let end = if error.is_some() {
+                "ROLLBACK"
+            } else {
+                "COMMIT"
+            };
+            inner.conn.execute_batch(end)?;
+        }
+        error.map_or(Ok(()), Err)
+    }
+}
+
+impl Engine<SqliteStore, SqliteStore> {
+    /// Creates an engine persisting to the SQLite database at `path`,
+    /// resuming from the accounts and transactions it already contains.
+    pub fn open_sqlite(
+        config: EngineConfig,
+        path: &str,
+    ) -> Result<Self, StoreError> {
+        let store = SqliteStore::open(path)?;
+        Ok(Engine::with_stores(config, store.clone(), store))
+    }
+}
+
+fn account_from_row(row: &Row<'_>) -> rusqlite::Result<Account> {
+    Ok(Account {
+        client: row.get(0)?,
+        available: Amount::from_units(row.get(1)?),
+        held: Amount::from_units(row.get(2)?),
+        total: Amount::from_units(row.get(3)?),
+        locked: row.get(4)?,
+    })
+}
+
+fn kind_name(kind: TransactionType) -> &'static str {
+    match kind {
+        TransactionType::Deposit => "deposit",
+        TransactionType::Withdrawal => "withdrawal",
+        TransactionType::Dispute => "dispute",
+        TransactionType::Resolve => "resolve",
+        TransactionType::Chargeback => "chargeback",
+    }
+}
+
+fn state_name(state: TxState) -> &'static str {
+    match state {
+        TxState::Processed => "processed",
+        TxState::Disputed => "disputed",
+        TxState::Resolved => "resolved",
+        TxState::ChargedBack => "charged_back",
+    }
+}
+
+fn history_from_names(
+    kind: &str,
+    client: u16,
+    amount: i64,
+    state: &str,
+) -> Result<History, StoreError> {
+    let kind = match kind {
+        "deposit" => TransactionType::Deposit,
+        "withdrawal" => TransactionType::Withdrawal,
+        other => return Err(StoreError::Corrupt(format!("kind `{other}`"))),
+    };
+    let state = match state {
+        "processed" => TxState::Processed,
+        "disputed" => TxState::Disputed,
+        "resolved" => TxState::Resolved,
+        "charged_back" => TxState::ChargedBack,
+        other => return Err(StoreError::Corrupt(format!("state `{other}`"))),
+    };
+    let mut history = History::new(kind, Amount::from_units(amount), client);
+    history.state = state;
+    Ok(history)
+}
+
+impl AccountStore for SqliteStore {
+    fn get(&self, client: u16) -> Option<Account> {
+        let mut inner = self.lock();
+        let found = inner
+            .conn
+            .prepare_cached(
+                "SELECT client, available, held, total, locked
+                 FROM accounts WHERE client = ?1",
+            )
+            .and_then(|mut stmt| {
+                stmt.query_row([client], account_from_row).optional()
+            })
+            .map_err(StoreError::from);
+        inner.check(found).flatten()
+    }
+    fn insert(&mut self, client: u16, account: Account) {
+        let mut inner = self.lock();
+        let written = inner.begin().and_then(|()| {
+            let mut stmt = inner.conn.prepare_cached(
+                "INSERT OR REPLACE INTO accounts
+                 (client, available, held, total, locked)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            stmt.execute(params![
+                client,
+                account.available.units(),
+                account.held.units(),
+                account.total.units(),
+                account.locked,
+            ])?;
+            Ok(())
+        });
+        inner.check(written);
+    }
+    fn all(&self) -> Vec<Account> {
+        let mut inner = self.lock();
+        let accounts = inner
+            .conn
+            .prepare_cached(
+                "SELECT client, available, held, total, locked FROM accounts",
+            )
+            .and_then(|mut stmt| {
+                stmt.query_map([], account_from_row)?.collect()
+            })
+            .map_err(StoreError::from);
+        inner.check(accounts).unwrap_or_default()
+    }
+    fn commit(&mut self) -> Result<(), StoreError> {
+        SqliteStore::commit(self)
+    }
+}
+
+impl TransactionStore for SqliteStore {
+    fn get(&self, tx: u32) -> Option<History> {
+        let mut inner = self.lock();
+        let found = inner
+            .conn
+            .prepare_cached(
+                "SELECT kind, client, amount, state
+                 FROM transactions WHERE tx = ?1",
+            )
+            .and_then(|mut stmt| {
+                stmt.query_row([tx], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get::<_, String>(3)?,
+                    ))
+                })
+                .optional()
+            })
+            .map_err(StoreError::from)
+            .and_then(|found| {
+                found
+                    .map(|(kind, client, amount, state)| {
+                        history_from_names(&kind, client, amount, &state)
+                    })
+                    .transpose()
+            });
+        inner.check(found).flatten()
+    }
+    fn all(&self) -> Vec<(u32, History)> {
+        let mut inner = self.lock();
+        let rows = inner
+            .conn
+            .prepare_cached(
+                "SELECT tx, kind, client, amount, state FROM transactions",
+            )
+            .and_then(|mut stmt| {
+                stmt.query_map([], |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        row.get::<_, String>(4)?,
+                    ))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .map_err(StoreError::from)
+            .and_then(|rows| {
+                rows.into_iter()
+                    .map(|(tx, kind, client, amount, state)| {
+                        Ok((
+                            tx,
+                            history_from_names(&kind, client, amount, &state)?,
+                        ))
+                    })
+                    .collect()
+            });
+        inner.check(rows).unwrap_or_default()
+    }
+    fn insert(&mut self, tx: u32, history: History) {
+        let mut inner = self.lock();
+        let written = inner.begin().and_then(|()| {
+            let mut stmt = inner.conn.prepare_cached(
+                "INSERT OR REPLACE INTO transactions
+                 (tx, kind, client, amount, state)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            stmt.execute(params![
+                tx,
+                kind_name(history.kind),
+                history.client,
+                history.amount.units(),
+                state_name(history.state),
+            ])?;
+            Ok(())
+        });
+        inner.check(written);
+    }
+    fn commit(&mut self) -> Result<(), StoreError> {
+        SqliteStore::commit(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SqliteStore;
+    use crate::engine::Engine;
+    use crate::entities::policy::EngineConfig;
+    use crate::entities::transaction::{Transaction, TxOutcome, TxState};
+    use crate::errors::{AccountError, TransactionError};
+    use crate::tests::TempPath;
+
+    #[test]
+    fn test_resume() {
+        let temp = TempPath::new("resume.db");
+        let path = temp.path();
+        let amount = "1.5".parse().unwrap();
+        {
+            let engine = Engine::open_sqlite(EngineConfig::default(), &path);
+            assert!(engine.is_ok());
+            let mut engine = engine.unwrap();
+            for event in [
+                Transaction::deposit(1, 1, amount),
+                Transaction::deposit(2, 2, amount),
+                Transaction::dispute(1, 1),
+            ] {
+                assert!(matches!(engine.apply(event), TxOutcome::Applied));
+            }
+        }
+        let mut engine =
+            Engine::open_sqlite(EngineConfig::default(), &path).unwrap();
+        assert!(matches!(
+            engine.apply(Transaction::deposit(1, 1, amount)),
+            TxOutcome::Rejected(TransactionError::DuplicateTx(1))
+        ));
+        assert!(matches!(
+            engine.apply(Transaction::chargeback(1, 1)),
+            TxOutcome::Applied
+        ));
+        assert!(matches!(
+            engine.apply(Transaction::deposit(1, 3, amount)),
+            TxOutcome::Rejected(TransactionError::Account(
+                AccountError::AccountLocked
+            ))
+        ));
+        let accounts = engine.snapshot();
+        assert_eq!(accounts.len(), 2);
+        assert!(accounts[0].locked);
+        assert_eq!(accounts[0].total.to_string(), "0");
+        assert_eq!(accounts[1].available, amount);
+        assert_eq!(
+            engine.transaction(1).map(|t| t.state),
+            Some(TxState::ChargedBack)
+        );
+    }
+
+    #[test]
+    fn test_rejected_writes_nothing() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        let mut engine =
+            Engine::with_stores(EngineConfig::default(), store.clone(), store);
+        let withdrawal = Transaction::withdrawal(1, 1, "1".parse().unwrap());
+        assert!(matches!(engine.apply(withdrawal), TxOutcome::Rejected(_)));
+        assert_eq!(engine.transaction(1), None);
+        // The account is opened, as with the in-memory store.
+        assert_eq!(engine.snapshot().len(), 1);
+    }
+}
@@ -1,22 +1,122 @@
-//! Integration tests.
-#[cfg(test)]
+//! Integration tests, and helpers shared with the unit tests.
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// A reader that always fails, like a directory given as input.
+pub(crate) struct Failing;
+
+impl Read for Failing {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::other("broken input"))
+    }
+}
+
+/// In-memory writer that can be read back after writing, clones share it.
+#[derive(Clone, Default)]
+pub(crate) struct Shared(Arc<Mutex<Vec<u8>>>);
+
+impl Shared {
+    /// Everything written so far.
+    pub(crate) fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Same as [`Shared::contents`], as text.
+    pub(crate) fn text(&self) -> String {
+        String::from_utf8(self.contents()).unwrap()
+    }
+}
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A path in the temp dir, unique to the test process. Whatever is there is
+/// removed when it is created and again when it is dropped.
+pub(crate) struct TempPath(PathBuf);
+
+impl TempPath {
+    pub(crate) fn new(name: &str) -> Self {
+        let path = std::env::temp_dir()
+            .join(format!("paymentlib-{}-{name}", std::process::id()));
+        remove(&path);
+        TempPath(path)
+    }
+
+    /// The path, as the library takes it.
+    pub(crate) fn path(&self) -> String {
+        self.0.to_string_lossy().into_owned()
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        remove(&self.0);
+    }
+}
+
+/// Removes the file or directory at `path`, if any.
+fn remove(path: &Path) {
+    let _ = std::fs::remove_file(path);
+    let _ = std::fs::remove_dir_all(path);
+}
+
+#[cfg(feature = "runtime")]
+#[allow(clippy::module_inception)]
 mod tests {
     use crate::{
-        engine::run, entities::channel::create_engine_channel,
-        entities::transaction::Transaction, entities::EngineEvent,
-        filehandler::read_csv,
+        engine::Engine,
+        entities::account::Account,
+        entities::channel::{create_channel, create_engine_channel},
+        entities::policy::EngineConfig,
+        entities::transaction::{Transaction, TxOutcome, TxState},
+        entities::EngineEvent,
+        errors::{AccountError, EngineError, FileError, TransactionError},
+        filehandler::{open_input, read_csv},
+        replay, replay_into, run_from_async_reader, run_from_csv_with,
+        run_from_reader, run_stand_alone, run_stand_alone_durable,
+        run_stand_alone_sharded, run_stand_alone_with, run_with_report,
+        runner::{run, Persistence},
+        shard::run_sharded,
+        store::{AccountStore, History, TransactionStore},
+        tests::{Failing, Shared, TempPath},
+        verify_journal, InputFormat, ParseMode, RunConfig, RunSummary,
+        StateSnapshot, TransactionSnapshot, TransactionType, Wal, WalConfig,
     };
+    use std::collections::{BTreeMap, HashMap};
+    use std::fs::File;
+    use std::io::Read;
     macro_rules! test_csv {
         ($fname:expr) => {
             concat!(env!("CARGO_MANIFEST_DIR"), "/src/tests/data/", $fname)
         };
     }
+    /// The csv file at `path`, as input.
+    fn input(path: &str) -> File {
+        open_input(path, InputFormat::Csv).unwrap()
+    }
+    /// Runs `config` on `reader`, returning the result and the account report.
+    async fn reported<R: Read + Send + 'static>(
+        reader: R,
+        config: RunConfig,
+    ) -> (Result<RunSummary, EngineError>, String) {
+        let report = Shared::default();
+        let result =
+            run_with_report(reader, config, Box::new(report.clone())).await;
+        (result, report.text())
+    }
     macro_rules! test_client {
         ($handler:ident, $path:expr, $expected_output: expr) => {
             let (transmit, recv) = create_engine_channel();
             let $handler = tokio::spawn(async move {
                 let mut result = vec![];
-                let _ = run(recv, &mut result).await;
+                let _ = run(recv, &mut result, None, Engine::new(EngineConfig::default()), Persistence::default()).await;
                 assert_eq!(
                     String::from_utf8(result).unwrap(),
                     $expected_output
@@ -32,6 +132,8 @@ mod tests {
             }
             let report_res = transmit.0.send(EngineEvent::Report()).await;
             assert!(report_res.is_ok());
+            let shutdown_res = transmit.0.send(EngineEvent::Shutdown()).await;
+            assert!(shutdown_res.is_ok());
             let handler_res = $handler.await;
             assert!(handler_res.is_ok());
         };
@@ -48,6 +150,16 @@ mod tests {
         test_client!(handler, path, "client,available,held,total,locked\n1,0.1,0,0.1,false\n2,0.1234,0,0.1234,false\n3,0.1,0,0.1,false\n4,0.02,0,0.02,false\n5,0.1,0,0.1,false\n6,0,0,0,false\n7,0,0,0,false\n".to_string());
     }
     #[tokio::test]
+    async fn test_micro_deposits() {
+        let path = test_csv!("micro_deposit_test.csv");
+        test_client!(
+            handler,
+            path,
+            "client,available,held,total,locked\n1,0.1,0,0.1,false\n"
+                .to_string()
+        );
+    }
+    #[tokio::test]
     async fn test_duplicate_tx() {
         let path = test_csv!("duplicate_tx_test.csv");
         test_client!(handler, path, "client,available,held,total,locked\n1,2,0,2,false\n2,0,0,0,false\n".to_string());
@@ -78,6 +190,11 @@ mod tests {
         );
     }
     #[tokio::test]
+    async fn test_dispute_other_client() {
+        let path = test_csv!("dispute_other_client_test.csv");
+        test_client!(handler, path, "client,available,held,total,locked\n1,1,0,1,false\n2,2,0,2,false\n".to_string());
+    }
+    #[tokio::test]
     async fn test_resolve() {
         let path = test_csv!("resolve_test.csv");
         test_client!(
@@ -117,4 +234,690 @@ mod tests {
                 .to_string()
         );
     }
+    #[tokio::test]
+    async fn test_outcomes() {
+        let path = test_csv!("outcome_test.csv");
+        let (transmit, recv) = create_engine_channel();
+        let (outcome_transmit, mut outcome_recv) = create_channel();
+        let handler = tokio::spawn(async move {
+            let mut result = vec![];
+            let _ = run(
+                recv,
+                &mut result,
+                Some(outcome_transmit),
+                Engine::new(EngineConfig::default()),
+                Persistence::default(),
+            )
+            .await;
+            String::from_utf8(result).unwrap()
+        });
+        let content = read_csv(path);
+        assert!(content.is_ok());
+        let mut outcomes = vec![];
+        for transaction in content.unwrap().deserialize::<Transaction>() {
+            let tx = transaction.unwrap();
+            assert!(transmit.0.send(EngineEvent::Tx(tx)).await.is_ok());
+            outcomes.push(outcome_recv.receive.recv().await.unwrap());
+        }
+        assert!(transmit.0.send(EngineEvent::Report()).await.is_ok());
+        assert!(transmit.0.send(EngineEvent::Shutdown()).await.is_ok());
+        assert_eq!(
+            handler.await.unwrap(),
+            "client,available,held,total,locked\n1,0,0,0,true\n"
+        );
+        assert!(matches!(
+            outcomes.as_slice(),
+            [
+                TxOutcome::Applied,
+                TxOutcome::Rejected(TransactionError::Account(
+                    AccountError::InsufficientFunds
+                )),
+                TxOutcome::Rejected(TransactionError::UnknownTx(2)),
+                TxOutcome::Rejected(TransactionError::DuplicateTx(1)),
+                TxOutcome::Rejected(TransactionError::MissingAmount(4)),
+                TxOutcome::Rejected(TransactionError::InvalidTransition {
+                    tx: 1,
+                    state: TxState::Processed
+                }),
+                TxOutcome::Applied,
+                TxOutcome::Rejected(TransactionError::InvalidTransition {
+                    tx: 1,
+                    state: TxState::Disputed
+                }),
+                TxOutcome::Applied,
+                TxOutcome::Rejected(TransactionError::Account(
+                    AccountError::AccountLocked
+                )),
+            ]
+        ));
+    }
+    #[tokio::test]
+    async fn test_rejections() {
+        let path = test_csv!("outcome_test.csv");
+        let output = TempPath::new("rejections.csv");
+        let config = RunConfig {
+            rejections: Some(output.path()),
+            ..Default::default()
+        };
+        let (result, report) = reported(input(path), config).await;
+        assert!(result.is_ok());
+        assert_eq!(
+            report,
+            "client,available,held,total,locked\n1,0,0,0,true\n"
+        );
+        let result = std::fs::read_to_string(output.path());
+        assert!(result.is_ok());
+        assert_eq!(
+            result.unwrap(),
+            "line,reason,row\n\
+             3,insufficient_funds,\"withdrawal, 1, 2, 5.0\"\n\
+             4,unknown_tx,\"dispute, 1, 2,\"\n\
+             5,duplicate_tx,\"deposit, 1, 1, 2.0\"\n\
+             6,missing_amount,\"deposit, 1, 4,\"\n\
+             7,invalid_transition,\"resolve, 1, 1,\"\n\
+             9,invalid_transition,\"dispute, 1, 1,\"\n\
+             11,account_locked,\"deposit, 1, 3, 1.0\"\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_lenient_parsing() {
+        let path = test_csv!("malformed_test.csv");
+        let output = TempPath::new("malformed.csv");
+        let config = RunConfig {
+            rejections: Some(output.path()),
+            parse_mode: ParseMode::Lenient,
+            ..Default::default()
+        };
+        let (summary, report) = reported(input(path), config).await;
+        assert!(summary.is_ok());
+        assert_eq!(summary.unwrap().malformed, 4);
+        assert_eq!(
+            report,
+            "client,available,held,total,locked\n1,0.5,0,0.5,false\n"
+        );
+        let result = std::fs::read_to_string(output.path());
+        assert!(result.is_ok());
+        assert_eq!(
+            result.unwrap(),
+            "line,reason,row\n\
+             3,malformed,\"transfer, 1, 2, 1.0\"\n\
+             4,malformed,\"deposit, -1, 3, 1.0\"\n\
+             5,malformed,\"deposit, 1, 4, abc\"\n\
+             6,malformed,\"deposit, 1, 5, 0.12345\"\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_failing_reader() {
+        for parse_mode in [ParseMode::Lenient, ParseMode::Strict] {
+            let input = b"type, client, tx, amount\ndeposit, 1, 1, 1.0\n";
+            let config = RunConfig {
+                parse_mode,
+                ..Default::default()
+            };
+            let (result, report) = reported(input.chain(Failing), config).await;
+            assert!(matches!(
+                result,
+                Err(EngineError::File(FileError::Read(_)))
+            ));
+            // Fails before the report, nothing is written.
+            assert_eq!(report, "");
+        }
+    }
+    #[tokio::test]
+    async fn test_strict_parsing() {
+        let path = test_csv!("malformed_test.csv");
+        let config = RunConfig {
+            parse_mode: ParseMode::Strict,
+            ..Default::default()
+        };
+        assert!(matches!(
+            run_from_csv_with(path, config).await,
+            Err(EngineError::ParseRow(_))
+        ));
+    }
+    #[tokio::test]
+    async fn test_reader() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1.0\nbogus\n";
+        let (summary, report) =
+            reported(input.as_bytes(), RunConfig::default()).await;
+        assert!(summary.is_ok());
+        assert_eq!(summary.unwrap().malformed, 1);
+        assert_eq!(
+            report,
+            "client,available,held,total,locked\n1,1,0,1,false\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_async_reader() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1.0\nbogus\n";
+        let output = TempPath::new("async-accounts.csv");
+        let config = RunConfig {
+            output: Some(output.path()),
+            ..Default::default()
+        };
+        let summary = run_from_async_reader(input.as_bytes(), config).await;
+        assert!(summary.is_ok());
+        assert_eq!(summary.unwrap().malformed, 1);
+        assert_eq!(
+            std::fs::read_to_string(output.path()).unwrap(),
+            "client,available,held,total,locked\n1,1,0,1,false\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_missing_file() {
+        let path = test_csv!("does_not_exist.csv");
+        assert!(matches!(
+            run_from_csv_with(path, RunConfig::default()).await,
+            Err(EngineError::File(FileError::NotFound(_)))
+        ));
+    }
+    #[tokio::test]
+    async fn test_wrong_extension() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml");
+        assert!(matches!(
+            run_from_csv_with(path, RunConfig::default()).await,
+            Err(EngineError::File(FileError::WrongExtension(_)))
+        ));
+    }
+    #[tokio::test]
+    async fn test_json_lines() {
+        let path = test_csv!("dispute_test.jsonl");
+        let output = TempPath::new("accounts.csv");
+        let config = RunConfig {
+            format: InputFormat::JsonLines,
+            output: Some(output.path()),
+            ..Default::default()
+        };
+        assert!(run_from_csv_with(path, config).await.is_ok());
+        let result = std::fs::read_to_string(output.path());
+        assert!(result.is_ok());
+        assert_eq!(
+            result.unwrap(),
+            "client,available,held,total,locked\n1,0.5,1,1.5,false\n"
+        );
+        // The extension must match the format.
+        let config = RunConfig {
+            format: InputFormat::JsonLines,
+            ..Default::default()
+        };
+        assert!(matches!(
+            run_from_csv_with(test_csv!("dispute_test.csv"), config).await,
+            Err(EngineError::File(FileError::WrongExtension(_)))
+        ));
+    }
+    #[tokio::test]
+    async fn test_engine_handle() {
+        let handle = run_stand_alone(EngineConfig::default());
+        let sender = handle.sender();
+        let content = read_csv(test_csv!("dispute_test.csv"));
+        assert!(content.is_ok());
+        for transaction in content.unwrap().deserialize::<Transaction>() {
+            let tx = EngineEvent::Tx(transaction.unwrap());
+            assert!(sender.send(tx).await.is_ok());
+        }
+        let accounts = handle.shutdown().await;
+        assert!(accounts.is_ok());
+        let accounts = accounts.unwrap();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].client, 1);
+        assert_eq!(accounts[0].available.to_string(), "0.5");
+        assert_eq!(accounts[0].held.to_string(), "1");
+        assert_eq!(accounts[0].total.to_string(), "1.5");
+        assert!(!accounts[0].locked);
+        // The engine is stopped, even though a sender is still alive.
+        assert!(sender.send(EngineEvent::Report()).await.is_err());
+    }
+    #[tokio::test]
+    async fn test_snapshot_keeps_running() {
+        let handle = run_stand_alone(EngineConfig::default());
+        let deposit = |tx, amount: &str| {
+            EngineEvent::Tx(Transaction::deposit(
+                1,
+                tx,
+                amount.parse().unwrap(),
+            ))
+        };
+        assert!(handle.send(deposit(1, "1.5")).await.is_ok());
+        let first = handle.snapshot().await;
+        assert!(first.is_ok());
+        assert_eq!(first.unwrap()[0].total.to_string(), "1.5");
+        assert!(handle.send(deposit(2, "1")).await.is_ok());
+        let (reply, response) = tokio::sync::oneshot::channel();
+        assert!(handle.send(EngineEvent::Snapshot(reply)).await.is_ok());
+        let second = response.await;
+        assert!(second.is_ok());
+        assert_eq!(second.unwrap()[0].total.to_string(), "2.5");
+        let accounts = handle.shutdown().await;
+        assert!(accounts.is_ok());
+        assert_eq!(accounts.unwrap()[0].total.to_string(), "2.5");
+    }
+    #[tokio::test]
+    async fn test_repeated_report() {
+        let (transmit, recv) = create_engine_channel();
+        let handler = tokio::spawn(async move {
+            let mut result = vec![];
+            let _ = run(
+                recv,
+                &mut result,
+                None,
+                Engine::new(EngineConfig::default()),
+                Persistence::default(),
+            )
+            .await;
+            String::from_utf8(result).unwrap()
+        });
+        let deposit = Transaction::deposit(1, 1, "1".parse().unwrap());
+        for event in [
+            EngineEvent::Report(),
+            EngineEvent::Tx(deposit),
+            EngineEvent::Report(),
+            EngineEvent::Shutdown(),
+        ] {
+            assert!(transmit.0.send(event).await.is_ok());
+        }
+        assert_eq!(
+            handler.await.unwrap(),
+            "client,available,held,total,locked\n1,1,0,1,false\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_lookups() {
+        let handle = run_stand_alone(EngineConfig::default());
+        let amount = "1.5".parse().unwrap();
+        for event in [
+            Transaction::deposit(1, 1, amount),
+            Transaction::dispute(1, 1),
+            Transaction::chargeback(1, 1),
+        ] {
+            assert!(handle.send(EngineEvent::Tx(event)).await.is_ok());
+        }
+        let account = handle.account(1).await;
+        assert!(account.is_ok());
+        let account = account.unwrap();
+        assert!(account.is_some_and(|a| a.locked));
+        let transaction = handle.transaction(1).await;
+        assert!(transaction.is_ok());
+        assert_eq!(
+            transaction.unwrap(),
+            Some(TransactionSnapshot {
+                tx: 1,
+                typename: TransactionType::Deposit,
+                client: 1,
+                amount,
+                state: TxState::ChargedBack,
+            })
+        );
+        let (reply, response) = tokio::sync::oneshot::channel();
+        let event = EngineEvent::GetAccount { client: 2, reply };
+        assert!(handle.send(event).await.is_ok());
+        assert!(matches!(response.await, Ok(None)));
+        let (reply, response) = tokio::sync::oneshot::channel();
+        let event = EngineEvent::GetTransaction { tx: 2, reply };
+        assert!(handle.send(event).await.is_ok());
+        assert!(matches!(response.await, Ok(None)));
+        assert!(handle.shutdown().await.is_ok());
+    }
+    #[tokio::test]
+    async fn test_sharded_matches_single() {
+        let shards = std::num::NonZeroUsize::new(3).unwrap();
+        let single = run_stand_alone(EngineConfig::default());
+        let sharded = run_stand_alone_sharded(EngineConfig::default(), shards);
+        let content = read_csv(test_csv!("dispute_other_client_test.csv"));
+        assert!(content.is_ok());
+        for transaction in content.unwrap().deserialize::<Transaction>() {
+            let tx = transaction.unwrap();
+            assert!(single.send(EngineEvent::Tx(tx.clone())).await.is_ok());
+            assert!(sharded.send(EngineEvent::Tx(tx)).await.is_ok());
+        }
+        let expected = single.snapshot().await.unwrap();
+        assert_eq!(sharded.snapshot().await.unwrap(), expected);
+        for client in [1, 2, 3] {
+            assert_eq!(
+                sharded.account(client).await.unwrap(),
+                single.account(client).await.unwrap()
+            );
+        }
+        assert_eq!(
+            sharded.transaction(2).await.unwrap(),
+            single.transaction(2).await.unwrap()
+        );
+        assert_eq!(sharded.shutdown().await.unwrap(), expected);
+        assert_eq!(single.shutdown().await.unwrap(), expected);
+    }
+    #[tokio::test]
+    async fn test_sharded_colliding_tx_ids() {
+        let single = run_stand_alone(EngineConfig::default());
+        let sharded = run_stand_alone_sharded(
+            EngineConfig::default(),
+            std::num::NonZeroUsize::new(2).unwrap(),
+        );
+        let amount = |text: &str| text.parse().unwrap();
+        // Clients 1 and 2 live on different shards, and reuse tx 7 and 8.
+        for tx in [
+            Transaction::deposit(1, 7, amount("1")),
+            Transaction::deposit(2, 7, amount("5")),
+            Transaction::withdrawal(2, 8, amount("9")),
+            Transaction::deposit(1, 8, amount("2")),
+            Transaction::deposit(2, 8, amount("3")),
+            Transaction::deposit(1, 7, amount("4")),
+            Transaction::dispute(2, 7),
+        ] {
+            assert!(single.send(EngineEvent::Tx(tx.clone())).await.is_ok());
+            assert!(sharded.send(EngineEvent::Tx(tx)).await.is_ok());
+        }
+        let expected = single.shutdown().await.unwrap();
+        let balances: Vec<_> =
+            expected.iter().map(|a| a.total.to_string()).collect();
+        assert_eq!(balances, ["3", "0"]);
+        assert_eq!(sharded.shutdown().await.unwrap(), expected);
+    }
+    #[tokio::test]
+    async fn test_sharded_report_is_ordered() {
+        let (transmit, recv) = create_engine_channel();
+        let shards = std::num::NonZeroUsize::new(4).unwrap();
+        let handler = tokio::spawn(async move {
+            let mut result = vec![];
+            let config = EngineConfig::default();
+            let _ = run_sharded(recv, &mut result, config, shards).await;
+            String::from_utf8(result).unwrap()
+        });
+        for client in (1..=6).rev() {
+            let deposit = Transaction::deposit(
+                client,
+                client.into(),
+                "1".parse().unwrap(),
+            );
+            assert!(transmit.0.send(EngineEvent::Tx(deposit)).await.is_ok());
+        }
+        for event in [EngineEvent::Report(), EngineEvent::Shutdown()] {
+            assert!(transmit.0.send(event).await.is_ok());
+        }
+        let mut expected = "client,available,held,total,locked\n".to_string();
+        for client in 1..=6 {
+            expected += &format!("{client},1,0,1,false\n");
+        }
+        assert_eq!(handler.await.unwrap(), expected);
+    }
+    /// Account store ordered by client.
+    #[derive(Default)]
+    struct OrderedStore(BTreeMap<u16, Account>);
+    impl AccountStore for OrderedStore {
+        fn get(&self, client: u16) -> Option<Account> {
+            self.0.get(&client).cloned()
+        }
+        fn insert(&mut self, client: u16, account: Account) {
+            self.0.insert(client, account);
+        }
+        fn all(&self) -> Vec<Account> {
+            self.0.values().cloned().collect()
+        }
+    }
+    #[tokio::test]
+    async fn test_custom_store() {
+        let amount = "1.5".parse().unwrap();
+        let mut account = Account::new(1);
+        assert!(account.deposit(&amount).is_ok());
+        let mut accounts = OrderedStore::default();
+        accounts.insert(1, account);
+        let mut transactions: HashMap<u32, History> = HashMap::new();
+        let deposit = History::new(TransactionType::Deposit, amount, 1);
+        TransactionStore::insert(&mut transactions, 1, deposit);
+        let engine = Engine::with_stores(
+            EngineConfig::default(),
+            accounts,
+            transactions,
+        );
+        let handle = run_stand_alone_with(engine);
+        let dispute = EngineEvent::Tx(Transaction::dispute(1, 1));
+        assert!(handle.send(dispute).await.is_ok());
+        let account = handle.account(1).await.unwrap().unwrap();
+        assert_eq!(account.held, amount);
+        assert_eq!(
+            handle.transaction(1).await.unwrap().map(|t| t.state),
+            Some(TxState::Disputed)
+        );
+        assert!(handle.shutdown().await.is_ok());
+    }
+    #[tokio::test]
+    async fn test_journal_resume() {
+        let temp = TempPath::new("journal.csv");
+        let journal = temp.path();
+        for _ in 0..2 {
+            let config = RunConfig {
+                journal: Some(journal.clone()),
+                ..Default::default()
+            };
+            let path = test_csv!("dispute_test.csv");
+            let (result, report) = reported(input(path), config).await;
+            assert!(result.is_ok());
+            // The report ends with the head of the journal as written.
+            let head = verify_journal(File::open(&journal).unwrap()).unwrap();
+            assert_eq!(
+                report,
+                format!(
+                    "client,available,held,total,locked\n\
+                     1,0.5,1,1.5,false\n# journal head: {head}\n"
+                )
+            );
+        }
+        // The second run resumed, every transaction was rejected.
+        let content = std::fs::read_to_string(&journal).unwrap();
+        assert_eq!(
+            content.lines().filter(|l| l.contains(",applied,")).count(),
+            4
+        );
+        // The chain continues across runs.
+        let file = std::fs::File::open(&journal).unwrap();
+        assert!(verify_journal(file).is_ok());
+        let engine = replay(&journal, EngineConfig::default());
+        assert!(engine.is_ok());
+        let accounts = engine.unwrap().snapshot();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].available.to_string(), "0.5");
+        assert_eq!(accounts[0].held.to_string(), "1");
+    }
+    #[tokio::test]
+    async fn test_snapshot_resume() {
+        let temps = [
+            TempPath::new("tail.csv"),
+            TempPath::new("snapshot.json"),
+            TempPath::new("tail-accounts.csv"),
+        ];
+        let (journal, snapshot) = (temps[0].path(), temps[1].path());
+        let run = |rows: &str, snapshot: Option<String>| {
+            let input = format!("type, client, tx, amount\n{rows}");
+            let config = RunConfig {
+                output: Some(temps[2].path()),
+                journal: Some(journal.clone()),
+                snapshot,
+                ..Default::default()
+            };
+            run_from_reader(std::io::Cursor::new(input.into_bytes()), config)
+        };
+        let rows =
+            "deposit, 1, 1, 1.0\ndeposit, 1, 3, 2.0\nwithdrawal, 1, 4, 1.5\n";
+        assert!(run(rows, Some(snapshot.clone())).await.is_ok());
+        // Journaled after the snapshot was written, as after a crash.
+        assert!(run("dispute, 1, 1,\n", None).await.is_ok());
+        let taken = StateSnapshot::read(&snapshot).unwrap();
+        assert_eq!(taken.position, 3);
+        assert_eq!(taken.accounts[0].held.to_string(), "0");
+        // Resumes from the snapshot and the journal tail.
+        assert!(run("", Some(snapshot.clone())).await.is_ok());
+        let taken = StateSnapshot::read(&snapshot).unwrap();
+        assert_eq!(taken.position, 4);
+        assert_eq!(taken.accounts[0].available.to_string(), "0.5");
+        assert_eq!(taken.accounts[0].held.to_string(), "1");
+    }
+    #[tokio::test]
+    async fn test_snapshot_without_journal() {
+        let temps = [
+            TempPath::new("alone.json"),
+            TempPath::new("alone-accounts.csv"),
+        ];
+        let (snapshot, output) = (temps[0].path(), temps[1].path());
+        let run = |rows: &str, every| {
+            let input = format!("type, client, tx, amount\n{rows}");
+            let config = RunConfig {
+                output: Some(output.clone()),
+                snapshot: Some(snapshot.clone()),
+                snapshot_every: std::num::NonZeroU64::new(every),
+                ..Default::default()
+            };
+            run_from_reader(std::io::Cursor::new(input.into_bytes()), config)
+        };
+        let deposits = "deposit, 1, 1, 1\ndeposit, 1, 2, 1\ndeposit, 1, 3, 1\n";
+        assert!(run(deposits, 2).await.is_ok());
+        // The deposit after the last periodic snapshot is not lost.
+        let taken = StateSnapshot::read(&snapshot).unwrap();
+        assert_eq!(taken.position, 3);
+        assert_eq!(taken.accounts[0].total.to_string(), "3");
+        assert!(run("deposit, 1, 4, 5\n", 0).await.is_ok());
+        let report = std::fs::read_to_string(&output).unwrap();
+        assert_eq!(
+            report,
+            "client,available,held,total,locked\n1,8,0,8,false\n"
+        );
+        assert_eq!(StateSnapshot::read(&snapshot).unwrap().position, 4);
+    }
+    #[tokio::test]
+    async fn test_wal_checkpoint() {
+        let temps = [
+            TempPath::new("checkpoint"),
+            TempPath::new("checkpoint.json"),
+            TempPath::new("checkpoint-accounts.csv"),
+        ];
+        let wal = WalConfig {
+            segment_bytes: 1,
+            ..WalConfig::new(temps[0].path())
+        };
+        let config = || RunConfig {
+            wal: Some(wal.clone()),
+            snapshot: Some(temps[1].path()),
+            output: Some(temps[2].path()),
+            ..Default::default()
+        };
+        let path = test_csv!("dispute_test.csv");
+        assert!(run_from_csv_with(path, config()).await.is_ok());
+        let segments = || std::fs::read_dir(&wal.dir).unwrap().count();
+        assert_eq!(segments(), 4);
+        // The snapshot holds every logged transaction, only the segment
+        // appended to is kept.
+        let input = std::io::Cursor::new(b"type, client, tx, amount\n");
+        assert!(run_from_reader(input, config()).await.is_ok());
+        assert_eq!(segments(), 1);
+        let report = std::fs::read_to_string(temps[2].path()).unwrap();
+        assert_eq!(
+            report,
+            "client,available,held,total,locked\n1,0.5,1,1.5,false\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_write_snapshot() {
+        let temp = TempPath::new("on-demand.json");
+        let path = temp.path();
+        let handle = run_stand_alone(EngineConfig::default());
+        let deposit = Transaction::deposit(1, 1, "1.5".parse().unwrap());
+        assert!(handle.send(EngineEvent::Tx(deposit)).await.is_ok());
+        assert!(handle.write_snapshot(&path).await.is_ok());
+        let taken = StateSnapshot::read(&path).unwrap();
+        assert_eq!(taken.position, 1);
+        assert_eq!(taken.transactions.len(), 1);
+        assert!(handle.shutdown().await.is_ok());
+        let sharded = run_stand_alone_sharded(
+            EngineConfig::default(),
+            std::num::NonZeroUsize::new(2).unwrap(),
+        );
+        assert!(matches!(
+            sharded.write_snapshot(&path).await,
+            Err(EngineError::File(FileError::Snapshot(_)))
+        ));
+        assert!(sharded.shutdown().await.is_ok());
+    }
+    #[tokio::test]
+    async fn test_wal_recovery() {
+        let dir = TempPath::new("wal");
+        let wal = WalConfig::new(dir.path());
+        let handle =
+            run_stand_alone_durable(EngineConfig::default(), wal.clone());
+        assert!(handle.is_ok());
+        let handle = handle.unwrap();
+        let amount = "1.5".parse().unwrap();
+        for event in [
+            Transaction::deposit(1, 1, amount),
+            Transaction::deposit(1, 2, amount),
+            Transaction::dispute(1, 1),
+        ] {
+            assert!(handle.send(EngineEvent::Tx(event)).await.is_ok());
+        }
+        // The engine is gone, the log is not.
+        drop(handle);
+        let handle =
+            run_stand_alone_durable(EngineConfig::default(), wal.clone());
+        let handle = handle.unwrap();
+        let account = handle.account(1).await.unwrap().unwrap();
+        assert_eq!(account.available, amount);
+        assert_eq!(account.held, amount);
+        let chargeback = EngineEvent::Tx(Transaction::chargeback(1, 1));
+        // Senders of the handle go through the log too.
+        assert!(handle.sender().send(chargeback).await.is_ok());
+        assert!(handle.shutdown().await.is_ok());
+        // A csv run resumes from the log too, and appends to it.
+        let config = RunConfig {
+            wal: Some(wal.clone()),
+            ..Default::default()
+        };
+        let path = test_csv!("dispute_test.csv");
+        let (result, report) = reported(input(path), config).await;
+        assert!(result.is_ok());
+        assert_eq!(
+            report,
+            "client,available,held,total,locked\n1,1.5,0,1.5,true\n"
+        );
+        let mut wal = Wal::open(wal).unwrap();
+        let mut engine = Engine::new(EngineConfig::default());
+        assert_eq!(wal.replay_into(&mut engine, 0).unwrap(), 8);
+        let accounts = engine.snapshot();
+        assert!(accounts[0].locked);
+        assert_eq!(accounts[0].total, amount);
+    }
+    #[tokio::test]
+    async fn test_wal_recovery_journaled() {
+        let dir = TempPath::new("wal-journaled");
+        let temp = TempPath::new("wal-journal.csv");
+        let wal = WalConfig::new(dir.path());
+        // Logged, but the engine never saw them.
+        {
+            let mut log = Wal::open(wal.clone()).unwrap();
+            for tx in [10, 11] {
+                let deposit =
+                    Transaction::deposit(2, tx, "1.5".parse().unwrap());
+                assert!(log.append(&deposit).is_ok());
+            }
+        }
+        let config = RunConfig {
+            journal: Some(temp.path()),
+            wal: Some(wal.clone()),
+            ..Default::default()
+        };
+        let path = test_csv!("dispute_test.csv");
+        let (result, report) = reported(input(path), config).await;
+        assert!(result.is_ok());
+        // The recovered transactions are journaled before the new ones.
+        let journal = std::fs::read(temp.path()).unwrap();
+        let head = verify_journal(journal.as_slice()).unwrap();
+        assert_eq!(
+            report,
+            format!(
+                "client,available,held,total,locked\n\
+                 1,0.5,1,1.5,false\n2,3,0,3,false\n# journal head: {head}\n"
+            )
+        );
+        let mut replayed = Engine::new(EngineConfig::default());
+        assert_eq!(replay_into(&mut replayed, journal.as_slice()).unwrap(), 6);
+        let mut engine = Engine::new(EngineConfig::default());
+        let mut log = Wal::open(wal).unwrap();
+        assert_eq!(log.replay_into(&mut engine, 0).unwrap(), 6);
+        assert_eq!(replayed.snapshot(), engine.snapshot());
+    }
 }
@@ -0,0 +1,325 @@
+//! Dry-run validation of transaction input.
+//!
+//! Every row is parsed with the same reader settings as processing, nothing
+//! is applied, and every problem is collected instead of stopping at the
+//! first. Only the ids of deposits and withdrawals are kept, to find
+//! duplicates and references to unknown transactions.
+use crate::entities::amount::Amount;
+use crate::entities::transaction::{Transaction, TransactionType};
+use crate::errors::{AmountError, EngineError, FileError};
+use crate::filehandler::{
+    csv_to_stdout, open_input, transaction_rows, InputFormat, ParsedRow,
+};
+use csv::DeserializeErrorKind;
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{Read, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// Kind of problem found in the input.
+pub enum IssueKind {
+    /// The row does not match the input schema.
+    Schema,
+    /// A number does not fit its field, or an amount is not positive.
+    OutOfRange,
+    /// A deposit or withdrawal without an amount.
+    MissingAmount,
+    /// A dispute, resolve or chargeback with an amount.
+    UnexpectedAmount,
+    /// A deposit or withdrawal reusing the id of an earlier one.
+    DuplicateTx,
+    /// A dispute, resolve or chargeback of a transaction not seen before.
+    UnknownTx,
+}
+impl IssueKind {
+    /// Machine-readable issue code.
+    pub fn code(&self) -> &'static str {
+        match self {
+            IssueKind::Schema => "schema",
+            IssueKind::OutOfRange => "out_of_range",
+            IssueKind::MissingAmount => "missing_amount",
+            IssueKind::UnexpectedAmount => "unexpected_amount",
+            IssueKind::DuplicateTx => "duplicate_tx",
+            IssueKind::UnknownTx => "unknown_tx",
+        }
+    }
+}
+impl Serialize for IssueKind {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(self.code())
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+/// A problem found in the input.
+pub struct Issue {
+    /// Line of the row in the input, a csv header is line 1.
+    pub line: u64,
+    /// What is wrong.
+    pub kind: IssueKind,
+    /// Details, for humans.
+    pub message: String,
+    /// The row as read, without its line ending.
+    pub row: String,
+}
+
+#[derive(Default, Debug)]
+/// Outcome of [`validate`].
+pub struct Validation {
+    /// Number of rows read.
+    pub rows: u64,
+    /// Every problem found, in input order.
+    pub issues: Vec<Issue>,
+}
+
+impl Validation {
+    /// Whether no problem was found.
+    #[must_use]
+    pub fn is_valid(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// Number of issues of each kind found.
+    #[must_use]
+    pub fn counts(&self) -> BTreeMap<IssueKind, usize> {
+        let mut counts = BTreeMap::new();
+        for issue in &self.issues {
+            *counts.entry(issue.kind).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    /// Writes every issue as csv to `stream`.
+    pub fn report<S: Write>(&self, stream: S) -> Result<(), FileError> {
+        csv_to_stdout(&self.issues, None, stream)
+    }
+}
+
+/// One line summary, e.g. `6 rows, 3 issues (schema: 2, unknown_tx: 1)`.
+impl fmt::Display for Validation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} rows, {} issues", self.rows, self.issues.len())?;
+        let counts = self.counts();
+        if !counts.is_empty() {
+            let counts: Vec<_> = counts
+                .iter()
+                .map(|(kind, count)| format!("{}: {count}", kind.code()))
+                .collect();
+            write!(f, " ({})", counts.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks every row of `reader` in `format`, without applying anything.
+/// Fails only if the input can not be read, e.g. a missing csv header or a
+/// failing reader.
+pub fn validate<R: Read>(
+    reader: R,
+    format: InputFormat,
+) -> Result<Validation, EngineError> {
+    let mut validation = Validation::default();
+    // Line of every deposit and withdrawal id.
+    let mut seen: HashMap<u32, u64> = HashMap::new();
+    for ParsedRow { line, raw, tx } in transaction_rows(reader, format)? {
+        validation.rows += 1;
+        let found = match tx {
+            Ok(tx) => check(&tx, line, &mut seen),
+            Err(EngineError::File(e)) => return Err(e.into()),
+            Err(e) => {
+                let kind = if out_of_range(&raw, &e) {
+                    IssueKind::OutOfRange
+                } else {
+                    IssueKind::Schema
+                };
+                Some((kind, e.to_string()))
+            }
+        };
+        if let Some((kind, message)) = found {
+            validation.issues.push(Issue {
+                line,
+                kind,
+                message,
+                row: raw,
+            });
+        }
+    }
+    Ok(validation)
+}
+
+/// Checks every row of the file at `path`, see [`validate`].
+/// Fails if the file does not exist or has the wrong extension for `format`.
+pub fn validate_file(
+    path: &str,
+    format: InputFormat,
+) -> Result<Validation, EngineError> {
+    validate(open_input(path, format)?, format)
+}
+
+/// Checks a parsed transaction on `line` against the ids `seen` so far.
+fn check(
+    tx: &Transaction,
+    line: u64,
+    seen: &mut HashMap<u32, u64>,
+) -> Option<(IssueKind, String)> {
+    let id = tx.tx();
+    match (tx.typename(), tx.amount()) {
+        (TransactionType::Deposit | TransactionType::Withdrawal, None) => {
+            Some((
+                IssueKind::MissingAmount,
+                format!("Transaction {id} has no amount"),
+            ))
+        }
+        (TransactionType::Deposit | TransactionType::Withdrawal, Some(a))
+            if a <= Amount::ZERO =>
+        {
+            Some((
+                IssueKind::OutOfRange,
+                format!("Transaction {id} amount {a} is not positive"),
+            ))
+        }
+        (TransactionType::Deposit | TransactionType::Withdrawal, Some(_)) => {
+            match seen.get(&id) {
+                Some(first) => Some((
+                    IssueKind::DuplicateTx,
+                    format!("Transaction {id} was first seen on line {first}"),
+                )),
+                None => {
+                    seen.insert(id, line);
+                    None
+                }
+            }
+        }
+        (_, Some(_)) => Some((
+            IssueKind::UnexpectedAmount,
+            format!("Reference to transaction {id} has an amount"),
+        )),
+        (_, None) if !seen.contains_key(&id) => Some((
+            IssueKind::UnknownTx,
+            format!("Transaction {id} was not seen before"),
+        )),
+        (_, None) => None,
+    }
+}
+
+/// Whether a row that failed to parse, as `raw`, holds an integer that does
+/// not fit its field or an amount that does not fit [`Amount`].
+fn out_of_range(raw: &str, e: &EngineError) -> bool {
+    match e {
+        EngineError::ParseRow(e) => {
+            let csv::ErrorKind::Deserialize { err, .. } = e.kind() else {
+                return false;
+            };
+            let mut fields = raw.split(',').map(str::trim);
+            match (err.kind(), err.field()) {
+                // An integer, but not of the field's type.
+                (DeserializeErrorKind::ParseInt(_), Some(field)) => fields
+                    .nth(field as usize)
+                    .is_some_and(|text| text.parse::<i128>().is_ok()),
+                // Custom errors, such as amount errors, carry no field.
+                (DeserializeErrorKind::Message(_), _) => {
+                    fields.any(amount_overflows)
+                }
+                _ => false,
+            }
+        }
+        EngineError::ParseJson { .. } => serde_json::from_str::<Value>(raw)
+            .is_ok_and(|row| {
+                let beyond = |field: &str, max: u64| {
+                    row.get(field).and_then(Value::as_number).is_some_and(|n| {
+                        n.as_u64().map_or(n.is_i64(), |n| n > max)
+                    })
+                };
+                beyond("client", u64::from(u16::MAX))
+                    || beyond("tx", u64::from(u32::MAX))
+                    || row
+                        .get("amount")
+                        .and_then(Value::as_str)
+                        .is_some_and(amount_overflows)
+            }),
+        _ => false,
+    }
+}
+
+/// Whether `text` is a decimal amount too large for [`Amount`].
+fn amount_overflows(text: &str) -> bool {
+    matches!(text.parse::<Amount>(), Err(AmountError::Overflow(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{validate, IssueKind};
+    use crate::errors::{EngineError, FileError};
+    use crate::filehandler::InputFormat;
+    use crate::tests::Failing;
+    use std::io::Read;
+
+    #[test]
+    fn test_validate_csv() {
+        let input = "type, client, tx, amount\n\
+                     deposit, 1, 1, 1.0\n\
+                     deposit, x, 2, 1.0\n\
+                     refund, 1, 3, 1.0\n\
+                     deposit, 70000, 4, 1.0\n\
+                     deposit, 1, 5, 99999999999999999\n\
+                     withdrawal, 1, 6, -1.0\n\
+                     withdrawal, 1, 7,\n\
+                     dispute, 1, 1, 1.0\n\
+                     deposit, 2, 1, 2.0\n\
+                     resolve, 1, 8,\n\
+                     chargeback, 1, 1,\n";
+        let validation = validate(input.as_bytes(), InputFormat::Csv).unwrap();
+        assert_eq!(validation.rows, 11);
+        let found: Vec<_> =
+            validation.issues.iter().map(|i| (i.line, i.kind)).collect();
+        assert_eq!(
+            found,
+            [
+                (3, IssueKind::Schema),
+                (4, IssueKind::Schema),
+                (5, IssueKind::OutOfRange),
+                (6, IssueKind::OutOfRange),
+                (7, IssueKind::OutOfRange),
+                (8, IssueKind::MissingAmount),
+                (9, IssueKind::UnexpectedAmount),
+                (10, IssueKind::DuplicateTx),
+                (11, IssueKind::UnknownTx),
+            ]
+        );
+        assert_eq!(
+            validation.to_string(),
+            "11 rows, 9 issues (schema: 2, out_of_range: 3, \
+             missing_amount: 1, unexpected_amount: 1, duplicate_tx: 1, \
+             unknown_tx: 1)"
+        );
+    }
+
+    #[test]
+    fn test_validate_json_lines() {
+        let input = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1.5"}
+
+{"type": "withdrawal", "client": 1, "tx": 2, "amount": 1.5}
+{"type": "deposit", "client": -1, "tx": 3, "amount": "1.5"}
+{"type": "dispute", "client": 1, "tx": 1}
+"#;
+        let validation =
+            validate(input.as_bytes(), InputFormat::JsonLines).unwrap();
+        assert_eq!(validation.rows, 4);
+        let found: Vec<_> =
+            validation.issues.iter().map(|i| (i.line, i.kind)).collect();
+        assert_eq!(found, [(3, IssueKind::Schema), (4, IssueKind::OutOfRange)]);
+    }
+
+    #[test]
+    fn test_validate_failing_reader() {
+        for format in [InputFormat::Csv, InputFormat::JsonLines] {
+            let input = b"{}\n".chain(Failing);
+            assert!(matches!(
+                validate(input, format),
+                Err(EngineError::File(FileError::Read(_)))
+            ));
+        }
+    }
+}
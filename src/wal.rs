@@ -0,0 +1,450 @@
+//! Write-ahead log in front of the engine.
+//!
+//! Transactions are appended to the log before the engine receives them, and
+//! synced to disk as the [`SyncPolicy`] says. The log is a directory of
+//! segments, `00000001.wal`, `00000002.wal`, ..., a new segment is started
+//! once the current one holds `segment_bytes`. Every record is framed as
+//!
+//! ``` text
+//! length: u32 le | crc32 of payload: u32 le | payload: json
+//! ```
+//!
+//! where the payload holds the transaction and its position, counted like
+//! the journal and snapshots. A crash while appending leaves a torn final
+//! record, [`Wal::open`] detects it by its length or checksum and truncates it.
+//!
+//! Segments only holding transactions already in a snapshot or the journal
+//! are removed by [`Wal::checkpoint`].
+use crate::engine::Engine;
+use crate::entities::transaction::Transaction;
+use crate::errors::FileError;
+use crate::store::{AccountStore, TransactionStore};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Length and checksum of a record.
+const HEADER: usize = 8;
+
+/// Segment size used by [`WalConfig::new`], 64 MiB.
+pub const DEFAULT_SEGMENT_BYTES: u64 = 64 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// When appended transactions are synced to disk.
+pub enum SyncPolicy {
+    /// After every transaction, an acknowledged send survives a crash.
+    Always,
+    /// After every n transactions, a crash loses at most the last n - 1.
+    Every(NonZeroU64),
+    /// Once the interval passed since the last sync, a crash loses at most
+    /// the transactions of the last interval.
+    Interval(Duration),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Where and how to write the log.
+pub struct WalConfig {
+    /// Directory holding the segments, created if needed.
+    pub dir: String,
+    /// When to sync to disk.
+    pub sync: SyncPolicy,
+    /// Size after which a new segment is started.
+    pub segment_bytes: u64,
+}
+
+impl WalConfig {
+    /// Log in `dir`, synced after every transaction.
+    pub fn new(dir: impl Into<String>) -> Self {
+        WalConfig {
+            dir: dir.into(),
+            sync: SyncPolicy::Always,
+            segment_bytes: DEFAULT_SEGMENT_BYTES,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+/// Payload of a record.
+struct Record<T> {
+    position: u64,
+    tx: T,
+}
+
+/// Write-ahead log writer, see the module documentation for the format.
+pub struct Wal {
+    dir: PathBuf,
+    sync: SyncPolicy,
+    segment_bytes: u64,
+    /// Index of the segment appended to.
+    segment: u64,
+    wtr: BufWriter<File>,
+    /// Bytes in the current segment.
+    written: u64,
+    /// Position of the last appended transaction.
+    position: u64,
+    /// Transactions appended since the last sync.
+    unsynced: u64,
+    last_sync: Instant,
+}
+
+impl Wal {
+    /// Opens the log in `config.dir`, creating it if needed.
+    /// A torn record at the end of the last segment is truncated, anywhere
+    /// else the log is corrupt and refused.
+    pub fn open(config: WalConfig) -> Result<Self, FileError> {
+        let dir = PathBuf::from(&config.dir);
+        fs::create_dir_all(&dir).map_err(FileError::Wal)?;
+        let segments = segments(&dir)?;
+        let mut position = 0;
+        for (i, (_, path)) in segments.iter().enumerate() {
+            let bytes = fs::read(path).map_err(FileError::Wal)?;
+            let valid = scan(&bytes, |record| position = record.position);
+            if valid == bytes.len() {
+                continue;
+            }
+            if i + 1 < segments.len() {
+                return Err(corrupt(path, valid));
+            }
+            warn!(
+                segment = %path.display(),
+                dropped = bytes.len() - valid,
+                "Truncating torn write-ahead log record"
+            );
+            let file = OpenOptions::new()
+                .write(true)
+                .open(path)
+                .map_err(FileError::Wal)?;
+            file.set_len(valid as u64).map_err(FileError::Wal)?;
+            file.sync_all().map_err(FileError::Wal)?;
+        }
+        let segment = segments.last().map_or(1, |(index, _)| *index);
+        let file = open_segment(&dir, segment)?;
+        let written = file.metadata().map_err(FileError::Wal)?.len();
+        Ok(Wal {
+            dir,
+            sync: config.sync,
+            segment_bytes: config.segment_bytes,
+            segment,
+            wtr: BufWriter::new(file),
+            written,
+            position,
+            unsynced: 0,
+            last_sync: Instant::now(),
+        })
+    }
+
+    /// When the log is synced to disk.
+    #[must_use]
+    pub fn policy(&self) -> SyncPolicy {
+        self.sync
+    }
+
+    /// Position of the last appended transaction.
+    #[must_use]
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Applies every logged transaction after `position` to `engine`.
+    /// Returns the position after the log, at least `position`, new
+    /// transactions are appended after it.
+    pub fn replay_into<A: AccountStore, T: TransactionStore>(
+        &mut self,
+        engine: &mut Engine<A, T>,
+        position: u64,
+    ) -> Result<u64, FileError> {
+        self.recover(position, |transaction| {
+            engine.apply(transaction);
+            Ok(())
+        })
+    }
+
+    /// Same as [`Wal::replay_into`], passing every logged transaction after
+    /// `position` to `apply` instead, e.g. to journal it as it is applied.
+    /// Stops at the first error of `apply`.
+    pub fn recover<F: FnMut(Transaction) -> Result<(), FileError>>(
+        &mut self,
+        position: u64,
+        mut apply: F,
+    ) -> Result<u64, FileError> {
+        self.wtr.flush().map_err(FileError::Wal)?;
+        for (_, path) in segments(&self.dir)? {
+            let bytes = fs::read(&path).map_err(FileError::Wal)?;
+            let mut failed = None;
+            let valid = scan(&bytes, |record| {
+                if failed.is_none() && record.position > position {
+                    failed = apply(record.tx).err();
+                }
+            });
+            if let Some(e) = failed {
+                return Err(e);
+            }
+            if valid != bytes.len() {
+                return Err(corrupt(&path, valid));
+            }
+        }
+        self.position = self.position.max(position);
+        Ok(self.position)
+    }
+
+    /// Removes the segments before the current one that only hold
+    /// transactions up to `position`, e.g. those already in a snapshot or the
+    /// journal. Returns how many were removed.
+    pub fn checkpoint(&mut self, position: u64) -> Result<usize, FileError> {
+        let mut removed = 0;
+        for (index, path) in segments(&self.dir)? {
+            if index >= self.segment {
+                break;
+            }
+            let bytes = fs::read(&path).map_err(FileError::Wal)?;
+            let mut last = 0;
+            scan(&bytes, |record| last = record.position);
+            if last > position {
+                break;
+            }
+            fs::remove_file(&path).map_err(FileError::Wal)?;
+            removed += 1;
+        }
+        if removed > 0 {
+            sync_dir(&self.dir)?;
+        }
+        Ok(removed)
+    }
+
+    /// Appends `transaction`, syncing if the policy says so.
+    /// Returns its position.
+    pub fn append(
+        &mut self,
+        transaction: &Transaction,
+    ) -> Result<u64, FileError> {
+        if self.written >= self.segment_bytes {
+            self.rotate()?;
+        }
+        let record = Record {
+            position: self.position + 1,
+            tx: transaction,
+        };
+        let payload = serde_json::to_vec(&record)
+            .map_err(|e| FileError::Wal(e.into()))?;
+        let length = u32::try_from(payload.len())
+            .map_err(|e| FileError::Wal(io::Error::other(e)))?;
+        let checksum = crc32fast::hash(&payload);
+        for part in
+            [&length.to_le_bytes()[..], &checksum.to_le_bytes(), &payload]
+        {
+            self.wtr.write_all(part).map_err(FileError::Wal)?;
+        }
+        self.position += 1;
+        self.written += (HEADER + payload.len()) as u64;
+        self.unsynced += 1;
+        let due = match self.sync {
+            SyncPolicy::Always => true,
+            SyncPolicy::Every(n) => self.unsynced >= n.get(),
+            SyncPolicy::Interval(interval) => {
+                self.last_sync.elapsed() >= interval
+            }
+        };
+        if due {
+            self.sync()?;
+        }
+        Ok(self.position)
+    }
+
+    /// Syncs every appended transaction to disk.
+    pub fn sync(&mut self) -> Result<(), FileError> {
+        self.last_sync = Instant::now();
+        if self.unsynced == 0 {
+            return Ok(());
+        }
+        self.wtr.flush().map_err(FileError::Wal)?;
+        self.wtr.get_ref().sync_data().map_err(FileError::Wal)?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    /// Syncs the current segment and starts the next one.
+    fn rotate(&mut self) -> Result<(), FileError> {
+        self.sync()?;
+        self.segment += 1;
+        self.wtr = BufWriter::new(open_segment(&self.dir, self.segment)?);
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Drop for Wal {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync() {
+            warn!(error = %e, "Unable to sync write-ahead log");
+        }
+    }
+}
+
+/// Calls `visit` for every valid record in `bytes`, in order, and returns
+/// the length of the valid prefix.
+fn scan<F: FnMut(Record<Transaction>)>(bytes: &[u8], mut visit: F) -> usize {
+    let mut offset = 0;
+    while let Some(header) = bytes.get(offset..offset + HEADER) {
+        let length =
+            u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let checksum =
+            u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        let start = offset + HEADER;
+        let Some(payload) = bytes.get(start..start + length as usize) else {
+            break;
+        };
+        if crc32fast::hash(payload) != checksum {
+            break;
+        }
+        let Ok(record) = serde_json::from_slice(payload) else {
+            break;
+        };
+        visit(record);
+        offset = start + payload.len();
+    }
+    offset
+}
+
+/// Segments in `dir`, ordered by index.
+fn segments(dir: &Path) -> Result<Vec<(u64, PathBuf)>, FileError> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir).map_err(FileError::Wal)? {
+        let path = entry.map_err(FileError::Wal)?.path();
+        if path.extension().is_some_and(|e| e == "wal") {
+            let index = path.file_stem().and_then(|s| s.to_str()?.parse().ok());
+            if let Some(index) = index {
+                segments.push((index, path));
+            }
+        }
+    }
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+/// Opens segment `index` for appending, a new segment is synced into `dir`.
+fn open_segment(dir: &Path, index: u64) -> Result<File, FileError> {
+    let path = dir.join(format!("{index:08}.wal"));
+    let created = !path.exists();
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(FileError::Wal)?;
+    if created {
+        sync_dir(dir)?;
+    }
+    Ok(file)
+}
+
+/// Syncs the entries of `dir`, so created and removed segments survive a
+/// crash.
+fn sync_dir(dir: &Path) -> Result<(), FileError> {
+    File::open(dir)
+        .and_then(|d| d.sync_all())
+        .map_err(FileError::Wal)
+}
+
+fn corrupt(path: &Path, offset: usize) -> FileError {
+    FileError::WalCorrupt {
+        segment: path.display().to_string(),
+        offset: offset as u64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SyncPolicy, Wal, WalConfig};
+    use crate::engine::Engine;
+    use crate::entities::policy::EngineConfig;
+    use crate::entities::transaction::Transaction;
+    use crate::errors::FileError;
+    use crate::tests::TempPath;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+
+    fn deposits(wal: &mut Wal, txs: std::ops::Range<u32>) {
+        for tx in txs {
+            let deposit = Transaction::deposit(1, tx, "1".parse().unwrap());
+            assert!(wal.append(&deposit).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_torn_record() {
+        let dir = TempPath::new("wal-torn");
+        let config = WalConfig::new(dir.path());
+        {
+            let mut wal = Wal::open(config.clone()).unwrap();
+            deposits(&mut wal, 1..4);
+        }
+        let segment = format!("{}/00000001.wal", config.dir);
+        let length = fs::metadata(&segment).unwrap().len();
+        // A crash halfway through the fourth record.
+        let mut file = OpenOptions::new().append(true).open(&segment).unwrap();
+        assert!(file.write_all(&[40, 0, 0, 0, 1, 2, 3, 4, b'{']).is_ok());
+        drop(file);
+        let mut wal = Wal::open(config.clone()).unwrap();
+        assert_eq!(fs::metadata(&segment).unwrap().len(), length);
+        assert_eq!(wal.position(), 3);
+        deposits(&mut wal, 4..5);
+        let mut engine = Engine::new(EngineConfig::default());
+        assert_eq!(wal.replay_into(&mut engine, 1).unwrap(), 4);
+        assert_eq!(engine.snapshot()[0].total.to_string(), "3");
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = TempPath::new("wal-rotation");
+        let config = WalConfig {
+            sync: SyncPolicy::Every(std::num::NonZeroU64::new(2).unwrap()),
+            segment_bytes: 1,
+            ..WalConfig::new(dir.path())
+        };
+        {
+            let mut wal = Wal::open(config.clone()).unwrap();
+            deposits(&mut wal, 1..4);
+        }
+        let mut segments: Vec<_> = fs::read_dir(&config.dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        segments.sort();
+        assert_eq!(segments, ["00000001.wal", "00000002.wal", "00000003.wal"]);
+        // Corruption before the last segment is not a torn write.
+        let first = format!("{}/00000001.wal", config.dir);
+        assert!(OpenOptions::new()
+            .write(true)
+            .open(&first)
+            .and_then(|f| f.set_len(4))
+            .is_ok());
+        assert!(matches!(
+            Wal::open(config.clone()),
+            Err(FileError::WalCorrupt { offset: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let dir = TempPath::new("wal-checkpoint");
+        let config = WalConfig {
+            segment_bytes: 1,
+            ..WalConfig::new(dir.path())
+        };
+        let mut wal = Wal::open(config.clone()).unwrap();
+        deposits(&mut wal, 1..4);
+        // The current segment is kept, even if it is checkpointed.
+        assert_eq!(wal.checkpoint(1).unwrap(), 1);
+        assert_eq!(wal.checkpoint(3).unwrap(), 1);
+        assert_eq!(fs::read_dir(&config.dir).unwrap().count(), 1);
+        drop(wal);
+        let mut wal = Wal::open(config).unwrap();
+        assert_eq!(wal.position(), 3);
+        let mut engine = Engine::new(EngineConfig::default());
+        assert_eq!(wal.replay_into(&mut engine, 2).unwrap(), 3);
+        assert_eq!(engine.snapshot()[0].total.to_string(), "1");
+    }
+}